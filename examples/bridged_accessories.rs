@@ -3,7 +3,7 @@ use std::sync::{Arc, Mutex};
 use hap::{
     accessory::{bridge, door, outlet, security_system, valve, Category, Information},
     characteristic::{Characteristic, Readable, Updatable},
-    transport::{IpTransport, Transport},
+    transport::{IpTransport, Status, Transport},
     Config,
     HapType,
 };
@@ -33,11 +33,12 @@ impl Readable<bool> for VirtualOutlet {
 }
 
 impl Updatable<bool> for VirtualOutlet {
-    fn on_update(&mut self, old_val: &bool, new_val: &bool, _: HapType) {
+    fn on_update(&mut self, old_val: &bool, new_val: &bool, _: HapType) -> Result<(), Status> {
         println!("Outlet: On updated from {} to {}.", old_val, new_val);
         if new_val != old_val {
             self.inner.lock().unwrap().on = *new_val;
         }
+        Ok(())
     }
 }
 
@@ -78,7 +79,7 @@ impl Readable<u8> for VirtualDoor {
 }
 
 impl Updatable<u8> for VirtualDoor {
-    fn on_update(&mut self, old_val: &u8, new_val: &u8, hap_type: HapType) {
+    fn on_update(&mut self, old_val: &u8, new_val: &u8, hap_type: HapType) -> Result<(), Status> {
         match hap_type {
             HapType::CurrentPosition => {
                 println!("Door: Current position updated from {} to {}.", old_val, new_val);
@@ -94,11 +95,14 @@ impl Updatable<u8> for VirtualDoor {
                         inner.target_position = *new_val;
                         inner.current_position = *new_val;
                     }
-                    self.current_position.set_value(*new_val).expect("couldn't set value");
+                    self.current_position
+                        .set_value(*new_val)
+                        .map_err(|_| Status::ServiceCommunicationFailure)?;
                 }
             },
             _ => {},
         }
+        Ok(())
     }
 }
 
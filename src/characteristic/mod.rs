@@ -1,16 +1,28 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    cmp::Ordering,
+    sync::{Arc, Mutex},
+};
 
 use erased_serde::{self, __internal_serialize_trait_object, serialize_trait_object};
+use async_trait::async_trait;
+use futures::{
+    future::{self, BoxFuture},
+    lock::Mutex as AsyncMutex,
+    TryFutureExt,
+};
 use serde::{
     ser::{SerializeStruct, Serializer},
     Deserialize,
     Serialize,
 };
 use serde_json::{self, json};
+use uuid::Uuid;
 
 use crate::{
-    event::{Event, EventEmitterPtr},
+    event::{ConnectionId, Event, EventEmitterPtr},
+    transport::Status,
     Error,
+    ErrorKind,
     HapType,
     Result,
 };
@@ -43,6 +55,9 @@ pub struct Inner<T: Default + Clone + Serialize> {
 
     readable: Option<Box<dyn Readable<T> + Send>>,
     updatable: Option<Box<dyn Updatable<T> + Send>>,
+    async_readable: Option<Arc<AsyncMutex<Box<dyn AsyncReadable<T> + Send>>>>,
+    async_updatable: Option<Arc<AsyncMutex<Box<dyn AsyncUpdatable<T> + Send>>>>,
+    additional_authorization: Option<Box<dyn AdditionalAuthorization + Send>>,
 
     event_emitter: Option<EventEmitterPtr>,
 }
@@ -109,7 +124,9 @@ where
             .event_notifications)
     }
 
-    /// Sets the event notifications value of a Characteristic.
+    /// Sets the event notifications value of a Characteristic. This is the `ev` value listed in the
+    /// Accessory database. Controllers subscribe to events per connection, so it doesn't affect which
+    /// events are sent.
     pub fn set_event_notifications(&mut self, event_notifications: Option<bool>) -> Result<()> {
         self.inner
             .lock()
@@ -135,41 +152,50 @@ where
         Ok(self.inner.lock().expect("couldn't access characteristic").value.clone())
     }
 
-    /// Sets the value of a Characteristic.
-    pub fn set_value(&mut self, val: T) -> Result<()> {
-        // TODO - check for min/max on types implementing PartialOrd
-        // if let Some(ref max) = self.inner.try_borrow()?.max_value {
-        //     if &val > max {
-        //         return Err(Error::from_str("value above max_value"));
-        //     }
-        // }
-        // if let Some(ref min) = self.inner.try_borrow()?.min_value {
-        //     if &val < min {
-        //         return Err(Error::from_str("value below min_value"));
-        //     }
-        // }
+    /// Sets the value of a Characteristic. Returns an `ErrorKind::InvalidValue` if the value violates
+    /// the constraints of the Characteristic and an `ErrorKind::HapStatus` if the `Updatable` rejects
+    /// the update. In both cases the value isn't changed. Only values written by a controller have to
+    /// lie on the grid of `step_value`, so e.g. sensor readings can be more precise.
+    pub fn set_value(&mut self, val: T) -> Result<()> { self.update_value(val, None) }
+
+    /// Sets the value of a Characteristic on behalf of the connection `origin`.
+    fn update_value(&mut self, val: T, origin: Option<ConnectionId>) -> Result<()> {
+        self.inner
+            .lock()
+            .expect("couldn't access characteristic")
+            .check_constraints(&val, origin.is_some())?;
 
         {
             let mut inner = self.inner.lock().expect("couldn't access characteristic");
             let old_val = inner.value.clone();
             let hap_type = inner.hap_type;
             if let Some(ref mut updatable) = inner.updatable {
-                updatable.on_update(&old_val, &val, hap_type);
+                updatable
+                    .on_update(&old_val, &val, hap_type)
+                    .map_err(|status| Error::from(ErrorKind::HapStatus(status)))?;
             }
         }
 
+        self.commit_value(val, origin)
+    }
+
+    /// Stores a value that already passed the constraint checks and the update hooks and emits a
+    /// `CharacteristicValueChanged` event for it. Whether the event is sent to a controller depends
+    /// on the event subscriptions of its connection only. The connection `origin` the value was written
+    /// by doesn't get the event.
+    fn commit_value(&mut self, val: T, origin: Option<ConnectionId>) -> Result<()> {
         {
             let inner = self.inner.lock().expect("couldn't access characteristic");
-            if inner.event_notifications == Some(true) {
-                if let Some(ref event_emitter) = inner.event_emitter {
-                    event_emitter.lock().expect("couldn't access event_emitter").emit(
-                        &Event::CharacteristicValueChanged {
-                            aid: inner.accessory_id,
-                            iid: inner.id,
-                            value: json!(&val),
-                        },
-                    );
-                }
+            if let Some(ref event_emitter) = inner.event_emitter {
+                event_emitter.lock().expect("couldn't access event_emitter").emit(
+                    &Event::CharacteristicValueChanged {
+                        aid: inner.accessory_id,
+                        iid: inner.id,
+                        hap_type: inner.hap_type,
+                        value: json!(&val),
+                        origin,
+                    },
+                );
             }
         }
 
@@ -248,6 +274,52 @@ where
         Ok(())
     }
 
+    /// Sets an `AsyncReadable` on the Characteristic. If set, it's used instead of the `Readable` for
+    /// remote reads.
+    pub fn set_async_readable(&mut self, async_readable: impl AsyncReadable<T> + 'static + Send) -> Result<()> {
+        self.inner.lock().expect("couldn't access characteristic").async_readable =
+            Some(Arc::new(AsyncMutex::new(Box::new(async_readable))));
+        Ok(())
+    }
+
+    /// Sets an `AsyncUpdatable` on the Characteristic. If set, it's used instead of the `Updatable`
+    /// for remote updates.
+    pub fn set_async_updatable(&mut self, async_updatable: impl AsyncUpdatable<T> + 'static + Send) -> Result<()> {
+        self.inner
+            .lock()
+            .expect("couldn't access characteristic")
+            .async_updatable = Some(Arc::new(AsyncMutex::new(Box::new(async_updatable))));
+        Ok(())
+    }
+
+    /// Sets an `AdditionalAuthorization` on the Characteristic. It's only consulted if the
+    /// Characteristic has `Perm::AdditionalAuthorization`.
+    pub fn set_additional_authorization(
+        &mut self,
+        additional_authorization: impl AdditionalAuthorization + 'static + Send,
+    ) -> Result<()> {
+        self.inner
+            .lock()
+            .expect("couldn't access characteristic")
+            .additional_authorization = Some(Box::new(additional_authorization));
+        Ok(())
+    }
+
+    /// Checks whether a Controller is authorized to write to a Characteristic with
+    /// `Perm::AdditionalAuthorization`. Writes to other Characteristics are always authorized.
+    pub fn authorize(&mut self, auth_data: Option<&[u8]>, controller_id: Option<Uuid>) -> Result<bool> {
+        let mut inner = self.inner.lock().expect("couldn't access characteristic");
+        if !inner.perms.contains(&Perm::AdditionalAuthorization) {
+            return Ok(true);
+        }
+        let hap_type = inner.hap_type;
+        Ok(match inner.additional_authorization {
+            Some(ref mut additional_authorization) =>
+                additional_authorization.authorize(auth_data, controller_id, hap_type),
+            None => true,
+        })
+    }
+
     /// Sets a `hap::event::EventEmitterPtr` on the Characteristic.
     pub fn set_event_emitter(&mut self, event_emitter: Option<EventEmitterPtr>) -> Result<()> {
         self.inner.lock().expect("couldn't access characteristic").event_emitter = event_emitter;
@@ -255,6 +327,186 @@ where
     }
 }
 
+impl<T: Default + Clone + Serialize + Send + Sync + 'static> Characteristic<T>
+where
+    for<'de> T: Deserialize<'de>,
+{
+    /// Returns a future resolving to the value of a Characteristic. The value is read through the
+    /// `AsyncReadable` if one is set and through `get_value` otherwise.
+    pub fn get_value_async(&mut self) -> BoxFuture<'static, Result<T>> {
+        let mut characteristic = self.clone();
+        Box::pin(async move {
+            let (async_readable, hap_type) = {
+                let inner = characteristic.inner.lock().expect("couldn't access characteristic");
+                (inner.async_readable.clone(), inner.hap_type)
+            };
+
+            match async_readable {
+                Some(async_readable) => {
+                    let val = async_readable
+                        .lock()
+                        .await
+                        .on_read(hap_type)
+                        .await
+                        .map_err(|status| Error::from(ErrorKind::HapStatus(status)))?;
+                    if let Some(v) = val {
+                        characteristic.set_value(v)?;
+                    }
+                    Ok(characteristic
+                        .inner
+                        .lock()
+                        .expect("couldn't access characteristic")
+                        .value
+                        .clone())
+                },
+                None => characteristic.get_value(),
+            }
+        })
+    }
+
+    /// Returns a future resolving once the value of a Characteristic is set. The update is passed
+    /// through the `AsyncUpdatable` if one is set and through `set_value` otherwise. The value isn't
+    /// changed if the future fails. `origin` is the connection writing the value, if any.
+    pub fn set_value_async(&mut self, val: T, origin: Option<ConnectionId>) -> BoxFuture<'static, Result<()>> {
+        let mut characteristic = self.clone();
+        Box::pin(async move {
+            let (async_updatable, old_val, hap_type) = {
+                let inner = characteristic.inner.lock().expect("couldn't access characteristic");
+                inner.check_constraints(&val, origin.is_some())?;
+                (inner.async_updatable.clone(), inner.value.clone(), inner.hap_type)
+            };
+
+            match async_updatable {
+                Some(async_updatable) => {
+                    async_updatable
+                        .lock()
+                        .await
+                        .on_update(&old_val, &val, hap_type)
+                        .await
+                        .map_err(|status| Error::from(ErrorKind::HapStatus(status)))?;
+                    characteristic.commit_value(val, origin)
+                },
+                None => characteristic.update_value(val, origin),
+            }
+        })
+    }
+
+    /// Converts a JSON value sent by a Controller to the value type of the Characteristic.
+    fn value_from_json(&self, value: serde_json::Value) -> Result<T> {
+        // the controller is setting boolean values
+        // either as a boolean or as an integer
+        if self.inner.lock().expect("couldn't access characteristic").format == Format::Bool && value.is_number() {
+            let num_v: u8 = serde_json::from_value(value).map_err(|e| invalid_value(e.to_string()))?;
+            if num_v == 0 {
+                Ok(serde_json::from_value(json!(false))?)
+            } else if num_v == 1 {
+                Ok(serde_json::from_value(json!(true))?)
+            } else {
+                Err(invalid_value(format!("{} is not a valid bool", num_v)))
+            }
+        } else {
+            serde_json::from_value(value).map_err(|e| invalid_value(e.to_string()))
+        }
+    }
+}
+
+impl<T: Default + Clone + Serialize> Inner<T> {
+    /// Checks a value against the min/max, step, valid values and length constraints of the
+    /// Characteristic. The step is only checked if `check_step` is set, i.e. for controller writes.
+    fn check_constraints(&self, val: &T, check_step: bool) -> Result<()> {
+        let v = json!(val);
+        match self.format {
+            Format::Bool => {},
+            Format::String => {
+                if let Some(max_len) = self.max_len {
+                    if v.as_str().map(|s| s.chars().count()).unwrap_or(0) > max_len as usize {
+                        return Err(invalid_value(format!("string longer than max_len {}", max_len)));
+                    }
+                }
+            },
+            Format::Tlv8 | Format::Data => {
+                if let Some(max_data_len) = self.max_data_len {
+                    if v.as_array().map(|a| a.len()).unwrap_or(0) > max_data_len as usize {
+                        return Err(invalid_value(format!("data longer than max_data_len {}", max_data_len)));
+                    }
+                }
+            },
+            _ => {
+                if let Some(ref max_value) = self.max_value {
+                    if compare_numbers(&v, &json!(max_value)) == Some(Ordering::Greater) {
+                        return Err(invalid_value(format!("{} is above max_value", v)));
+                    }
+                }
+                if let Some(ref min_value) = self.min_value {
+                    if compare_numbers(&v, &json!(min_value)) == Some(Ordering::Less) {
+                        return Err(invalid_value(format!("{} is below min_value", v)));
+                    }
+                }
+                match self.step_value {
+                    Some(ref step_value) if check_step => {
+                        let base = match self.min_value {
+                            Some(ref min_value) => json!(min_value),
+                            None => json!(0),
+                        };
+                        if !is_on_step(&v, &base, &json!(step_value)) {
+                            return Err(invalid_value(format!("{} is not a multiple of step_value", v)));
+                        }
+                    },
+                    _ => {},
+                }
+                if let Some(ref valid_values) = self.valid_values {
+                    if !valid_values
+                        .iter()
+                        .any(|valid_value| compare_numbers(&v, &json!(valid_value)) == Some(Ordering::Equal))
+                    {
+                        return Err(invalid_value(format!("{} is not a valid value", v)));
+                    }
+                }
+                if let Some([ref start, ref end]) = self.valid_values_range {
+                    if compare_numbers(&v, &json!(start)) == Some(Ordering::Less)
+                        || compare_numbers(&v, &json!(end)) == Some(Ordering::Greater)
+                    {
+                        return Err(invalid_value(format!("{} is outside of valid_values_range", v)));
+                    }
+                }
+            },
+        }
+
+        Ok(())
+    }
+}
+
+fn invalid_value(reason: String) -> Error { ErrorKind::InvalidValue(reason).into() }
+
+/// Compares two numeric JSON values, preferring exact integer comparison over floating point.
+fn compare_numbers(a: &serde_json::Value, b: &serde_json::Value) -> Option<Ordering> {
+    if let (Some(a), Some(b)) = (a.as_i64(), b.as_i64()) {
+        return Some(a.cmp(&b));
+    }
+    if let (Some(a), Some(b)) = (a.as_u64(), b.as_u64()) {
+        return Some(a.cmp(&b));
+    }
+    a.as_f64()?.partial_cmp(&b.as_f64()?)
+}
+
+/// Checks whether `val` lies on the grid of `step` starting from `base`. Floating point values are
+/// checked with a tolerance, as `f32` values don't convert exactly.
+fn is_on_step(val: &serde_json::Value, base: &serde_json::Value, step: &serde_json::Value) -> bool {
+    if let (Some(v), Some(b), Some(s)) = (val.as_i64(), base.as_i64(), step.as_i64()) {
+        return s <= 0 || (v as i128 - b as i128) % s as i128 == 0;
+    }
+    if let (Some(v), Some(b), Some(s)) = (val.as_u64(), base.as_u64(), step.as_u64()) {
+        return s == 0 || v < b || (v - b) % s == 0;
+    }
+    match (val.as_f64(), base.as_f64(), step.as_f64()) {
+        (Some(v), Some(b), Some(s)) if s > 0.0 => {
+            let steps = (v - b) / s;
+            (steps - steps.round()).abs() < 1e-3
+        },
+        _ => true,
+    }
+}
+
 impl<T: Default + Clone + Serialize> Serialize for Characteristic<T> {
     fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
         let mut state = serializer.serialize_struct("Characteristic", 15)?;
@@ -317,12 +569,23 @@ pub trait HapCharacteristic: erased_serde::Serialize {
     fn get_perms(&self) -> Result<Vec<Perm>>;
     /// Returns the event notifications value of a Characteristic.
     fn get_event_notifications(&self) -> Result<Option<bool>>;
-    /// Sets the event notifications value of a Characteristic.
+    /// Sets the event notifications value of a Characteristic. This is the `ev` value listed in the
+    /// Accessory database. Controllers subscribe to events per connection, so it doesn't affect which
+    /// events are sent.
     fn set_event_notifications(&mut self, event_notifications: Option<bool>) -> Result<()>;
     /// Returns the value of a Characteristic.
     fn get_value(&mut self) -> Result<serde_json::Value>;
-    /// Sets the value of a Characteristic.
-    fn set_value(&mut self, value: serde_json::Value) -> Result<()>;
+    /// Sets the value of a Characteristic. `origin` is the connection writing the value, if any.
+    fn set_value(&mut self, value: serde_json::Value, origin: Option<ConnectionId>) -> Result<()>;
+    /// Returns a future resolving to the value of a Characteristic.
+    fn get_value_async(&mut self) -> BoxFuture<'static, Result<serde_json::Value>>;
+    /// Returns a future resolving once the value of a Characteristic is set. `origin` is the
+    /// connection writing the value, if any.
+    fn set_value_async(
+        &mut self,
+        value: serde_json::Value,
+        origin: Option<ConnectionId>,
+    ) -> BoxFuture<'static, Result<()>>;
     /// Returns the `Unit` of a Characteristic.
     fn get_unit(&self) -> Result<Option<Unit>>;
     /// Returns the maximum value of a Characteristic.
@@ -333,13 +596,15 @@ pub trait HapCharacteristic: erased_serde::Serialize {
     fn get_step_value(&self) -> Result<Option<serde_json::Value>>;
     /// Returns the maximum length of a Characteristic.
     fn get_max_len(&self) -> Result<Option<u16>>;
+    /// Checks whether a Controller is authorized to write to a Characteristic.
+    fn authorize(&mut self, auth_data: Option<&[u8]>, controller_id: Option<Uuid>) -> Result<bool>;
     /// Sets a `hap::event::EventEmitterPtr` on the Characteristic.
     fn set_event_emitter(&mut self, event_emitter: Option<EventEmitterPtr>) -> Result<()>;
 }
 
 serialize_trait_object!(HapCharacteristic);
 
-impl<T: Default + Clone + Serialize> HapCharacteristic for Characteristic<T>
+impl<T: Default + Clone + Serialize + Send + Sync + 'static> HapCharacteristic for Characteristic<T>
 where
     for<'de> T: Deserialize<'de>,
 {
@@ -363,23 +628,24 @@ where
 
     fn get_value(&mut self) -> Result<serde_json::Value> { Ok(json!(self.get_value()?)) }
 
-    fn set_value(&mut self, value: serde_json::Value) -> Result<()> {
-        let v;
-        // the controller is setting boolean values
-        // either as a boolean or as an integer
-        if self.inner.lock().expect("couldn't access characteristic").format == Format::Bool && value.is_number() {
-            let num_v: u8 = serde_json::from_value(value)?;
-            if num_v == 0 {
-                v = serde_json::from_value(json!(false))?;
-            } else if num_v == 1 {
-                v = serde_json::from_value(json!(true))?;
-            } else {
-                return Err(Error::from_str("invalid value for bool characteristic"));
-            }
-        } else {
-            v = serde_json::from_value(value)?;
+    fn set_value(&mut self, value: serde_json::Value, origin: Option<ConnectionId>) -> Result<()> {
+        let v = self.value_from_json(value)?;
+        self.update_value(v, origin)
+    }
+
+    fn get_value_async(&mut self) -> BoxFuture<'static, Result<serde_json::Value>> {
+        Box::pin(self.get_value_async().map_ok(|v| json!(v)))
+    }
+
+    fn set_value_async(
+        &mut self,
+        value: serde_json::Value,
+        origin: Option<ConnectionId>,
+    ) -> BoxFuture<'static, Result<()>> {
+        match self.value_from_json(value) {
+            Ok(v) => self.set_value_async(v, origin),
+            Err(e) => Box::pin(future::err(e)),
         }
-        self.set_value(v)
     }
 
     fn get_unit(&self) -> Result<Option<Unit>> { self.get_unit() }
@@ -407,6 +673,10 @@ where
 
     fn get_max_len(&self) -> Result<Option<u16>> { self.get_max_len() }
 
+    fn authorize(&mut self, auth_data: Option<&[u8]>, controller_id: Option<Uuid>) -> Result<bool> {
+        self.authorize(auth_data, controller_id)
+    }
+
     fn set_event_emitter(&mut self, event_emitter: Option<EventEmitterPtr>) -> Result<()> {
         self.set_event_emitter(event_emitter)
     }
@@ -425,8 +695,39 @@ pub trait Updatable<T: Default + Serialize> {
     /// This function is called every time a Controller attempts to update the value of a
     /// `Characteristic`. `old_val` is a reference to the current value of the `Characteristic` and
     /// `new_val` is a reference to the value the Controller attempts to change the
-    /// `Characteristic`'s to.
-    fn on_update(&mut self, old_val: &T, new_val: &T, hap_type: HapType);
+    /// `Characteristic`'s to. Returning an `Err(Status)` rejects the update, leaves the value of the
+    /// `Characteristic` unchanged and reports the `Status` back to the Controller.
+    fn on_update(&mut self, old_val: &T, new_val: &T, hap_type: HapType) -> std::result::Result<(), Status>;
+}
+
+/// `AsyncReadable` can be implemented to react to the remote read of a `Characteristic` without
+/// blocking the server, e.g. when the value has to be fetched from a slow device.
+#[async_trait]
+pub trait AsyncReadable<T: Default + Serialize> {
+    /// This function is called every time a Controller attempts to read the value of a
+    /// `Characteristic`. The returned value works like the return value of `Readable::on_read`.
+    /// Returning an `Err(Status)` reports the `Status` back to the Controller.
+    async fn on_read(&mut self, hap_type: HapType) -> std::result::Result<Option<T>, Status>;
+}
+
+/// `AsyncUpdatable` can be implemented to react to the remote update of a `Characteristic` without
+/// blocking the server, e.g. when the value has to be written to a slow device.
+#[async_trait]
+pub trait AsyncUpdatable<T: Default + Serialize> {
+    /// This function is called every time a Controller attempts to update the value of a
+    /// `Characteristic`. The value of the `Characteristic` is changed once the function returns.
+    /// Returning an `Err(Status)` rejects the update and reports the `Status` back to the Controller.
+    async fn on_update(&mut self, old_val: &T, new_val: &T, hap_type: HapType) -> std::result::Result<(), Status>;
+}
+
+/// `AdditionalAuthorization` can be implemented to authorize remote updates of a `Characteristic`
+/// with `Perm::AdditionalAuthorization`, e.g. with a vendor specific token.
+pub trait AdditionalAuthorization {
+    /// This function is called every time a Controller attempts to update the value of a
+    /// `Characteristic` with `Perm::AdditionalAuthorization`. `auth_data` is the decoded `authData`
+    /// sent by the Controller and `controller_id` is the pairing ID of the Controller. Returning
+    /// `false` rejects the update with `Status::InsufficientPrivileges`.
+    fn authorize(&mut self, auth_data: Option<&[u8]>, controller_id: Option<Uuid>, hap_type: HapType) -> bool;
 }
 
 /// Permission of a `Characteristic`.
@@ -489,3 +790,68 @@ pub enum Format {
 impl Default for Format {
     fn default() -> Format { Format::String }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compare_numbers_across_representations() {
+        assert_eq!(compare_numbers(&json!(-5), &json!(3)), Some(Ordering::Less));
+        assert_eq!(compare_numbers(&json!(u64::MAX), &json!(i64::MAX)), Some(Ordering::Greater));
+        assert_eq!(compare_numbers(&json!(u64::MAX), &json!(-1)), Some(Ordering::Greater));
+        assert_eq!(compare_numbers(&json!(u64::MAX), &json!(u64::MAX)), Some(Ordering::Equal));
+        assert_eq!(compare_numbers(&json!(2), &json!(1.5)), Some(Ordering::Greater));
+        assert_eq!(compare_numbers(&json!(100.5f32), &json!(100)), Some(Ordering::Greater));
+        assert_eq!(compare_numbers(&json!("1"), &json!(1)), None);
+    }
+
+    #[test]
+    fn is_on_step_with_negative_base() {
+        assert!(is_on_step(&json!(-5), &json!(-10), &json!(5)));
+        assert!(is_on_step(&json!(15), &json!(-10), &json!(5)));
+        assert!(!is_on_step(&json!(-7), &json!(-10), &json!(5)));
+        assert!(is_on_step(&json!(-4.5f32), &json!(-10.0f32), &json!(0.5f32)));
+        assert!(!is_on_step(&json!(-4.4f32), &json!(-10.0f32), &json!(0.5f32)));
+    }
+
+    #[test]
+    fn is_on_step_above_i64_max() {
+        let big = i64::MAX as u64 + 1;
+        assert!(is_on_step(&json!(big), &json!(0), &json!(2)));
+        assert!(!is_on_step(&json!(big + 1), &json!(0), &json!(2)));
+        assert!(is_on_step(&json!(u64::MAX), &json!(u64::MAX - 10), &json!(5)));
+        assert!(!is_on_step(&json!(u64::MAX), &json!(u64::MAX - 10), &json!(3)));
+    }
+
+    #[test]
+    fn is_on_step_with_f32_values_near_the_grid() {
+        // none of these are exact in binary, but they're meant to lie on the grid
+        for v in &[0.1f32, 0.3, 21.3, 37.7, 99.9] {
+            assert!(is_on_step(&json!(v), &json!(0.0f32), &json!(0.1f32)), "{} is off the grid", v);
+        }
+        assert!(is_on_step(&json!(22.5f32), &json!(10.0f32), &json!(0.5f32)));
+        assert!(!is_on_step(&json!(21.35f32), &json!(0.0f32), &json!(0.1f32)));
+        assert!(!is_on_step(&json!(45.3f32), &json!(0.0f32), &json!(1.0f32)));
+        assert!(!is_on_step(&json!(45.3f32), &json!(0), &json!(1)));
+        assert!(is_on_step(&json!(45.0f32), &json!(0), &json!(1)));
+    }
+
+    #[test]
+    fn step_value_is_only_enforced_on_controller_writes() {
+        let mut characteristic = Characteristic::new(Inner::<f32> {
+            format: Format::Float,
+            min_value: Some(0.0),
+            max_value: Some(100.0),
+            step_value: Some(1.0),
+            ..Default::default()
+        });
+
+        assert!(characteristic.set_value(45.3).is_ok());
+        assert_eq!(characteristic.inner.lock().unwrap().value, 45.3);
+
+        assert!(characteristic.update_value(45.7, Some(ConnectionId(0))).is_err());
+        assert!(characteristic.update_value(46.0, Some(ConnectionId(0))).is_ok());
+        assert!(characteristic.set_value(100.5).is_err());
+    }
+}
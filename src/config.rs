@@ -5,6 +5,7 @@ use std::{
     net::IpAddr,
     str,
     sync::{Arc, Mutex},
+    time::Duration,
 };
 
 use eui48::MacAddress;
@@ -85,6 +86,20 @@ pub struct Config {
     pub feature_flag: FeatureFlag, // ff
     /// Optional maximum number of paired controllers.
     pub max_peers: Option<usize>,
+    /// Time a read or write of a single characteristic may take before it's answered with
+    /// `Status::OperationTimedOut`. Defaults to 10 seconds.
+    pub characteristic_timeout: Duration,
+    /// Time during which characteristic value changes are collected and sent to a controller as a
+    /// single event message. Defaults to 100 milliseconds.
+    pub event_batch_window: Duration,
+    /// Minimum time between two events for the same characteristic sent to a controller. Value
+    /// changes in between are coalesced, so the controller gets the latest value once the time has
+    /// passed. Events of a Programmable Switch Event characteristic aren't limited and are always sent
+    /// right away. Defaults to 1 second.
+    pub event_rate_limit: Duration,
+    /// Time during which a controller may resume a session via Pair Resume instead of running a full
+    /// Pair Verify. Defaults to 1 hour.
+    pub session_resume_expiry: Duration,
     pub version: u64,
     pub config_hash: Option<u64>,
 }
@@ -183,6 +198,10 @@ impl Default for Config {
             status_flag: StatusFlag::NotPaired,
             feature_flag: FeatureFlag::Zero,
             max_peers: None,
+            characteristic_timeout: Duration::from_secs(10),
+            event_batch_window: Duration::from_millis(100),
+            event_rate_limit: Duration::from_secs(1),
+            session_resume_expiry: Duration::from_secs(60 * 60),
             version: 0,
             config_hash: None,
         };
@@ -0,0 +1,48 @@
+use uuid::Uuid;
+
+use crate::protocol::Permissions;
+
+/// Operation a controller attempts on a Characteristic.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Operation {
+    /// Reading the value of a Characteristic or subscribing to its events.
+    Read,
+    /// Writing the value of a Characteristic.
+    Write,
+}
+
+/// `AccessPolicy` can be implemented to restrict the access of paired controllers to single
+/// Accessories or Characteristics.
+///
+/// # Examples
+///
+/// ```
+/// use hap::{
+///     db::{AccessPolicy, Operation},
+///     protocol::Permissions,
+/// };
+/// use uuid::Uuid;
+///
+/// /// Makes the Accessory with the ID 2 read-only for non-admin controllers.
+/// struct ReadOnlyForUsers;
+///
+/// impl AccessPolicy for ReadOnlyForUsers {
+///     fn is_allowed(&self, _: Uuid, permissions: &Permissions, aid: u64, _: u64, operation: Operation) -> bool {
+///         aid != 2 || operation == Operation::Read || permissions == &Permissions::Admin
+///     }
+/// }
+/// ```
+pub trait AccessPolicy {
+    /// This function is called every time a Controller attempts an `Operation` on the
+    /// Characteristic with the instance ID `iid` of the Accessory with the ID `aid`. `controller_id`
+    /// is the pairing ID of the Controller and `permissions` are the permissions of its pairing.
+    /// Returning `false` rejects the `Operation` with `Status::InsufficientPrivileges`.
+    fn is_allowed(
+        &self,
+        controller_id: Uuid,
+        permissions: &Permissions,
+        aid: u64,
+        iid: u64,
+        operation: Operation,
+    ) -> bool;
+}
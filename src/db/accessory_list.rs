@@ -1,22 +1,30 @@
 use std::sync::{Arc, Mutex};
 
 use erased_serde::{self, __internal_serialize_trait_object, serialize_trait_object};
+use futures::future::{self, BoxFuture};
 use serde::ser::{Serialize, SerializeStruct, Serializer};
 
 use crate::{
     accessory::HapAccessory,
     characteristic::Perm,
-    event::EventEmitterPtr,
+    db::{AccessPolicy, DatabasePtr, Operation},
+    event::{ConnectionId, EventEmitterPtr},
+    protocol::IdPtr,
     transport::http::{server::EventSubscriptions, ReadResponseObject, Status, WriteObject, WriteResponseObject},
     Error,
+    ErrorKind,
     Result,
 };
 
+type PendingRead = BoxFuture<'static, Result<serde_json::Value>>;
+type PendingWrite = BoxFuture<'static, Result<()>>;
+
 /// `AccessoryList` is a wrapper type holding an `Arc<Mutex>` with a `Vec` of boxed Accessories.
 #[derive(Clone)]
 pub struct AccessoryList {
     pub accessories: Arc<Mutex<Vec<AccessoryListPtr>>>,
     event_emitter: EventEmitterPtr,
+    access_policy: Arc<Mutex<Option<Box<dyn AccessPolicy + Send>>>>,
     id_count: u64,
 }
 
@@ -26,10 +34,45 @@ impl AccessoryList {
         AccessoryList {
             accessories: Arc::new(Mutex::new(Vec::new())),
             event_emitter,
+            access_policy: Arc::new(Mutex::new(None)),
             id_count: 1,
         }
     }
 
+    /// Sets an `AccessPolicy` restricting the access of paired controllers to the Accessories.
+    pub fn set_access_policy(&mut self, access_policy: impl AccessPolicy + 'static + Send) {
+        *self.access_policy.lock().expect("couldn't access access_policy") = Some(Box::new(access_policy));
+    }
+
+    /// Checks whether the `AccessPolicy`, if one is set, allows a controller an `Operation` on a
+    /// Characteristic. Unknown controllers are only allowed if no `AccessPolicy` is set.
+    fn is_allowed(
+        &self,
+        controller_id: &IdPtr,
+        database: &DatabasePtr,
+        aid: u64,
+        iid: u64,
+        operation: Operation,
+    ) -> Result<bool> {
+        let access_policy = self.access_policy.lock().expect("couldn't access access_policy");
+        if let Some(ref access_policy) = *access_policy {
+            let controller_id = match *controller_id.lock().expect("couldn't access controller_id") {
+                Some(controller_id) => controller_id,
+                None => return Ok(false),
+            };
+            let pairing = match database
+                .lock()
+                .expect("couldn't access database")
+                .get_pairing(controller_id)
+            {
+                Ok(pairing) => pairing,
+                Err(_) => return Ok(false),
+            };
+            return Ok(access_policy.is_allowed(controller_id, &pairing.permissions, aid, iid, operation));
+        }
+        Ok(true)
+    }
+
     /// Adds an Accessory to the `AccessoryList` and returns a pointer to the added Accessory.
     pub fn add_accessory(&mut self, accessory: Box<dyn AccessoryListMember + Send>) -> Result<AccessoryListPtr> {
         let mut a = accessory;
@@ -75,7 +118,56 @@ impl AccessoryList {
         perms: bool,
         hap_type: bool,
         ev: bool,
-    ) -> Result<ReadResponseObject> {
+        controller_id: &IdPtr,
+        database: &DatabasePtr,
+        event_subscriptions: &EventSubscriptions,
+    ) -> BoxFuture<'static, Result<ReadResponseObject>> {
+        match self.is_allowed(controller_id, database, aid, iid, Operation::Read) {
+            Ok(true) => {},
+            Ok(false) => {
+                return Box::pin(future::ok(ReadResponseObject {
+                    iid,
+                    aid,
+                    status: Some(Status::InsufficientPrivileges as i32),
+                    ..Default::default()
+                }));
+            },
+            Err(e) => return Box::pin(future::err(e)),
+        }
+
+        match self.prepare_read(aid, iid, meta, perms, hap_type, ev, event_subscriptions) {
+            Ok((mut result_object, Some(read))) => Box::pin(async move {
+                match read.await {
+                    Ok(value) => {
+                        result_object.value = Some(value);
+                    },
+                    Err(e) => match e.kind() {
+                        &ErrorKind::HapStatus(status) => {
+                            result_object.status = Some(status as i32);
+                        },
+                        _ => return Err(e),
+                    },
+                }
+                Ok(result_object)
+            }),
+            Ok((result_object, None)) => Box::pin(future::ok(result_object)),
+            Err(e) => Box::pin(future::err(e)),
+        }
+    }
+
+    /// Looks up a characteristic, fills in the requested metadata and returns the pending read of its
+    /// value. The accessory locks are only held while the read is started. The `ev` value reflects the
+    /// event subscriptions of the requesting connection.
+    fn prepare_read(
+        &self,
+        aid: u64,
+        iid: u64,
+        meta: bool,
+        perms: bool,
+        hap_type: bool,
+        ev: bool,
+        event_subscriptions: &EventSubscriptions,
+    ) -> Result<(ReadResponseObject, Option<PendingRead>)> {
         let mut result_object = ReadResponseObject {
             iid,
             aid,
@@ -91,6 +183,7 @@ impl AccessoryList {
             max_len: None,
             status: Some(0),
         };
+        let mut read = None;
 
         'l: for accessory in self.accessories.lock().expect("couldn't access accessories").iter_mut() {
             let mut a = accessory.lock().expect("couldn't access accessory");
@@ -100,7 +193,7 @@ impl AccessoryList {
                         if characteristic.get_id()? == iid {
                             let characteristic_perms = characteristic.get_perms()?;
                             if characteristic_perms.contains(&Perm::PairedRead) {
-                                result_object.value = Some(characteristic.get_value()?);
+                                read = Some(characteristic.get_value_async());
                                 if meta {
                                     result_object.format = Some(characteristic.get_format()?);
                                     result_object.unit = characteristic.get_unit()?;
@@ -116,7 +209,12 @@ impl AccessoryList {
                                     result_object.hap_type = Some(characteristic.get_type()?);
                                 }
                                 if ev {
-                                    result_object.ev = characteristic.get_event_notifications()?;
+                                    result_object.ev = Some(
+                                        event_subscriptions
+                                            .lock()
+                                            .expect("couldn't access event_subscriptions")
+                                            .contains(&(aid, iid)),
+                                    );
                                 }
                             } else {
                                 result_object.status = Some(Status::WriteOnlyCharacteristic as i32);
@@ -128,19 +226,90 @@ impl AccessoryList {
             }
         }
 
-        Ok(result_object)
+        Ok((result_object, read))
     }
 
     pub(crate) fn write_characteristic(
         &self,
         write_object: WriteObject,
+        timed_write: bool,
+        controller_id: &IdPtr,
+        database: &DatabasePtr,
         event_subscriptions: &EventSubscriptions,
-    ) -> Result<WriteResponseObject> {
+        connection_id: ConnectionId,
+    ) -> BoxFuture<'static, Result<WriteResponseObject>> {
+        let operation = match write_object.value {
+            Some(_) => Operation::Write,
+            None => Operation::Read,
+        };
+        match self.is_allowed(controller_id, database, write_object.aid, write_object.iid, operation) {
+            Ok(true) => {},
+            Ok(false) => {
+                return Box::pin(future::ok(WriteResponseObject {
+                    iid: write_object.iid,
+                    aid: write_object.aid,
+                    status: Status::InsufficientPrivileges as i32,
+                }));
+            },
+            Err(e) => return Box::pin(future::err(e)),
+        }
+
+        match self.prepare_write(
+            write_object,
+            timed_write,
+            controller_id,
+            event_subscriptions,
+            connection_id,
+        ) {
+            Ok((mut result_object, Some(write))) => Box::pin(async move {
+                if let Err(e) = write.await {
+                    match e.kind() {
+                        ErrorKind::InvalidValue(_) => {
+                            result_object.status = Status::InvalidValueInRequest as i32;
+                        },
+                        &ErrorKind::HapStatus(status) => {
+                            result_object.status = status as i32;
+                        },
+                        _ => return Err(e),
+                    }
+                }
+                Ok(result_object)
+            }),
+            Ok((result_object, None)) => Box::pin(future::ok(result_object)),
+            Err(e) => Box::pin(future::err(e)),
+        }
+    }
+
+    /// Looks up a characteristic, updates the event subscriptions and returns the pending write of
+    /// its value. The accessory locks are only held while the write is started. Characteristics with
+    /// `Perm::TimedWrite` are only written if the request is covered by a valid prepared Timed Write,
+    /// Characteristics with `Perm::AdditionalAuthorization` only if the Controller is authorized.
+    fn prepare_write(
+        &self,
+        write_object: WriteObject,
+        timed_write: bool,
+        controller_id: &IdPtr,
+        event_subscriptions: &EventSubscriptions,
+        connection_id: ConnectionId,
+    ) -> Result<(WriteResponseObject, Option<PendingWrite>)> {
         let mut result_object = WriteResponseObject {
             aid: write_object.aid,
             iid: write_object.iid,
             status: 0,
         };
+        let mut write = None;
+
+        let auth_data = match write_object.auth_data {
+            Some(ref auth_data) => match base64::decode(auth_data) {
+                Ok(auth_data) => Some(auth_data),
+                Err(_) => {
+                    result_object.status = Status::InvalidValueInRequest as i32;
+                    return Ok((result_object, write));
+                },
+            },
+            None => None,
+        };
+        let controller_id = *controller_id.lock().expect("couldn't access controller_id");
 
         let mut a = self.accessories.lock().expect("couldn't access accessories");
         'l: for accessory in a.iter_mut() {
@@ -152,7 +321,6 @@ impl AccessoryList {
                             let characteristic_perms = characteristic.get_perms()?;
                             if let Some(ev) = write_object.ev {
                                 if characteristic_perms.contains(&Perm::Events) {
-                                    characteristic.set_event_notifications(Some(ev))?;
                                     let subscription = (write_object.aid, write_object.iid);
                                     let mut es =
                                         event_subscriptions.lock().expect("couldn't access event_subscriptions");
@@ -171,10 +339,16 @@ impl AccessoryList {
                                 }
                             }
                             if let Some(value) = write_object.value {
-                                if characteristic_perms.contains(&Perm::PairedWrite) {
-                                    characteristic.set_value(value)?;
-                                } else {
+                                if !characteristic_perms.contains(&Perm::PairedWrite) {
                                     result_object.status = Status::ReadOnlyCharacteristic as i32;
+                                } else if characteristic_perms.contains(&Perm::TimedWrite) && !timed_write {
+                                    result_object.status = Status::InvalidValueInRequest as i32;
+                                } else if !characteristic
+                                    .authorize(auth_data.as_ref().map(|d| &d[..]), controller_id)?
+                                {
+                                    result_object.status = Status::InsufficientPrivileges as i32;
+                                } else {
+                                    write = Some(characteristic.set_value_async(value, Some(connection_id)));
                                 }
                             }
                             break 'l;
@@ -184,7 +358,7 @@ impl AccessoryList {
             }
         }
 
-        Ok(result_object)
+        Ok((result_object, write))
     }
 }
 
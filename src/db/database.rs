@@ -1,25 +1,55 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
+use rand::{self, Rng};
 use uuid::Uuid;
 
 use crate::{
-    db::{file_storage, storage::Storage},
+    db::{
+        file_storage,
+        key_provider::{EncryptionScope, KeyProvider},
+        storage::{is_not_found, Storage},
+    },
     protocol::{Device, Pairing},
 };
 
-use crate::Result;
+use crate::{Error, Result};
+
+/// Header of records encrypted at rest. Records without it are stored in plain text.
+const ENCRYPTED_RECORD_HEADER: &[u8] = b"hap-enc1";
+const NONCE_LEN: usize = 12;
+const AUTH_TAG_LEN: usize = 16;
 
 /// Pointer to a `Database`.
 pub type DatabasePtr = Arc<Mutex<Database>>;
 
+/// Unsuccessful Pair Setup attempts since the last successful one.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct PairSetupAttempts {
+    /// Number of unsuccessful attempts.
+    pub count: u64,
+    /// Time of the last unsuccessful attempt.
+    pub last_attempt: Option<SystemTime>,
+}
+
 /// `Database` is a wrapper type around a boxed implementor of the `Storage` trait.
 pub struct Database {
     storage: Box<dyn Storage + Send>,
+    key_provider: Option<Box<dyn KeyProvider + Send>>,
+    encryption_scope: EncryptionScope,
 }
 
 impl Database {
     /// Creates a new `Database`.
-    pub fn new(storage: Box<dyn Storage + Send>) -> Database { Database { storage } }
+    pub fn new(storage: Box<dyn Storage + Send>) -> Database {
+        Database {
+            storage,
+            key_provider: None,
+            encryption_scope: EncryptionScope::Device,
+        }
+    }
 
     /// Creates a new `Database` with a `FileStorage` as its `Storage`.
     pub fn new_with_file_storage(dir: &str) -> Result<Database> {
@@ -27,6 +57,13 @@ impl Database {
         Ok(Database::new(Box::new(storage)))
     }
 
+    /// Creates a new `Database` with a `SqliteStorage` at the given file path as its `Storage`.
+    #[cfg(feature = "sqlite")]
+    pub fn new_with_sqlite_storage(path: &str) -> Result<Database> {
+        let storage = crate::db::SqliteStorage::new(path)?;
+        Ok(Database::new(Box::new(storage)))
+    }
+
     /// Returns the stored value for a given key as a `Vec<u8>`.
     pub fn get_bytes(&self, key: &str) -> Result<Vec<u8>> {
         let k = format!("{}.entity", key);
@@ -41,29 +78,53 @@ impl Database {
         Ok(())
     }
 
+    /// Encrypts the records in the given `EncryptionScope` at rest with the key supplied by a
+    /// `KeyProvider`. Records already stored are rewritten right away, so records stored in plain
+    /// text before are encrypted and records outside of the scope are decrypted. Encrypted records
+    /// can't be read without the `KeyProvider`.
+    pub fn set_encryption(
+        &mut self,
+        key_provider: impl KeyProvider + 'static + Send,
+        encryption_scope: EncryptionScope,
+    ) -> Result<()> {
+        self.key_provider = Some(Box::new(key_provider));
+        self.encryption_scope = encryption_scope;
+
+        match self.get_device() {
+            Ok(device) => self.set_device(&device)?,
+            Err(ref e) if is_not_found(e) => {},
+            Err(e) => return Err(e),
+        }
+        for pairing in self.list_pairings()? {
+            self.set_pairing(&pairing)?;
+        }
+        Ok(())
+    }
+
     /// Returns the stored `Device`.
     pub fn get_device(&self) -> Result<Device> {
-        let device_bytes = self.get_bytes("device")?;
+        let device_bytes = self.get_record("device")?;
         Device::from_bytes(&device_bytes)
     }
 
     /// Stores the `Device`.
     pub fn set_device(&self, device: &Device) -> Result<()> {
         let device_bytes = device.as_bytes()?;
-        self.set_bytes("device", device_bytes)?;
+        self.set_record("device", device_bytes, self.key_provider.is_some())?;
         Ok(())
     }
 
     /// Returns the stored `Pairing` for a given `Uuid`.
     pub fn get_pairing(&self, id: Uuid) -> Result<Pairing> {
-        let pairing_bytes = self.get_bytes(&id.to_simple().to_string())?;
+        let pairing_bytes = self.get_record(&id.to_simple().to_string())?;
         Pairing::from_bytes(&pairing_bytes)
     }
 
     /// Stores a given `Pairing`.
     pub fn set_pairing(&self, pairing: &Pairing) -> Result<()> {
         let pairing_bytes = pairing.as_bytes()?;
-        self.set_bytes(&pairing.id.to_simple().to_string(), pairing_bytes)?;
+        let encrypt = self.key_provider.is_some() && self.encryption_scope == EncryptionScope::DeviceAndPairings;
+        self.set_record(&pairing.id.to_simple().to_string(), pairing_bytes, encrypt)?;
         Ok(())
     }
 
@@ -78,7 +139,7 @@ impl Database {
         let mut pairings = Vec::new();
         for key in self.storage.keys_with_suffix("entity")? {
             if &key != "device" {
-                let pairing_bytes = self.get_bytes(&key)?;
+                let pairing_bytes = self.get_record(&key)?;
                 let pairing = Pairing::from_bytes(&pairing_bytes)?;
                 pairings.push(pairing);
             }
@@ -96,4 +157,85 @@ impl Database {
         }
         Ok(count)
     }
+
+    /// Returns the stored unsuccessful Pair Setup attempts.
+    pub fn get_pair_setup_attempts(&self) -> Result<PairSetupAttempts> {
+        let count = self.get_u64_or_zero("pair_setup_attempts")?;
+        let last_attempt = Some(self.get_u64_or_zero("pair_setup_last_attempt")?)
+            .filter(|secs| *secs != 0)
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+        Ok(PairSetupAttempts { count, last_attempt })
+    }
+
+    /// Stores the unsuccessful Pair Setup attempts.
+    pub fn set_pair_setup_attempts(&self, attempts: &PairSetupAttempts) -> Result<()> {
+        self.storage.set_u64("pair_setup_attempts", attempts.count)?;
+        let last_attempt = attempts
+            .last_attempt
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.storage.set_u64("pair_setup_last_attempt", last_attempt)?;
+        Ok(())
+    }
+
+    /// Returns the stored `u64` for a given key, or 0 if none is stored. Any other error is returned,
+    /// so e.g. a Pair Setup lock can't be lifted by a failing storage.
+    fn get_u64_or_zero(&self, key: &str) -> Result<u64> {
+        match self.storage.get_u64(key) {
+            Ok(value) => Ok(value),
+            Err(ref e) if is_not_found(e) => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns the stored record for a given key, decrypting it if it's encrypted.
+    fn get_record(&self, key: &str) -> Result<Vec<u8>> {
+        let record = self.get_bytes(key)?;
+        if !record.starts_with(ENCRYPTED_RECORD_HEADER) {
+            return Ok(record);
+        }
+        let key_provider = self
+            .key_provider
+            .as_ref()
+            .ok_or(Error::from_str("record is encrypted, but no key provider is set"))?;
+
+        let encrypted = &record[ENCRYPTED_RECORD_HEADER.len()..];
+        if encrypted.len() < NONCE_LEN + AUTH_TAG_LEN {
+            return Err(Error::from_str("encrypted record is truncated"));
+        }
+        let (nonce, encrypted) = encrypted.split_at(NONCE_LEN);
+        let (data, auth_tag) = encrypted.split_at(encrypted.len() - AUTH_TAG_LEN);
+
+        // the key is authenticated as well, so records can't be swapped
+        let mut decrypted = Vec::new();
+        chacha20_poly1305_aead::decrypt(
+            &key_provider.key()?,
+            nonce,
+            key.as_bytes(),
+            data,
+            auth_tag,
+            &mut decrypted,
+        )?;
+        Ok(decrypted)
+    }
+
+    /// Stores a given record for a given key, encrypting it if `encrypt` is set.
+    fn set_record(&self, key: &str, value: Vec<u8>, encrypt: bool) -> Result<()> {
+        let key_provider = match self.key_provider {
+            Some(ref key_provider) if encrypt => key_provider,
+            _ => return self.set_bytes(key, value),
+        };
+
+        let nonce: [u8; NONCE_LEN] = rand::thread_rng().gen();
+        let mut encrypted = Vec::new();
+        let auth_tag =
+            chacha20_poly1305_aead::encrypt(&key_provider.key()?, &nonce, key.as_bytes(), &value, &mut encrypted)?;
+
+        let mut record = ENCRYPTED_RECORD_HEADER.to_vec();
+        record.extend_from_slice(&nonce);
+        record.extend_from_slice(&encrypted);
+        record.extend_from_slice(&auth_tag);
+        self.set_bytes(key, record)
+    }
 }
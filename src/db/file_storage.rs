@@ -1,20 +1,26 @@
 use std::{
     ffi::OsStr,
     fs,
-    io::{BufReader, BufWriter, Read, Write},
-    os::unix::fs::PermissionsExt,
+    io::{Read, Write},
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt},
     path::{Path, PathBuf},
-    str,
 };
 
-use byteorder::{BigEndian, ByteOrder, ReadBytesExt};
-use uuid::Uuid;
-
 use crate::db::storage::Storage;
 
 use crate::{Error, Result};
 
+/// Prefix of the temporary files values are written to before they replace the actual file.
+const TEMP_FILE_PREFIX: &str = ".";
+/// Suffix of the temporary files values are written to before they replace the actual file.
+const TEMP_FILE_SUFFIX: &str = ".tmp";
+
 /// `FileStorage` is an implementor of the `Storage` trait that stores data to the file system.
+///
+/// Every value is written to a temporary file that is synced to disk and then renamed to the actual
+/// file, so a value is either fully written or not at all. The storage directory and its files are
+/// only accessible by their owner, as they hold the accessory's private key.
+#[derive(Clone)]
 pub struct FileStorage {
     dir_path: PathBuf,
 }
@@ -23,12 +29,35 @@ impl FileStorage {
     /// Creates a new `FileStorage`.
     pub fn new(dir: &str) -> Result<FileStorage> {
         let path = Path::new(dir).to_path_buf();
-        fs::create_dir_all(&path)?;
+        fs::DirBuilder::new().recursive(true).mode(0o700).create(&path)?;
 
+        // the directory may have been created with broader permissions by an earlier version
         let mut perms = fs::metadata(&path)?.permissions();
-        perms.set_mode(0o777);
+        perms.set_mode(0o700);
         fs::set_permissions(&path, perms)?;
-        Ok(FileStorage { dir_path: path })
+
+        let file_storage = FileStorage { dir_path: path };
+        file_storage.remove_temp_files()?;
+        Ok(file_storage)
+    }
+
+    /// Returns all values stored in the given directory by their keys without creating or modifying
+    /// the directory. Temporary files of interrupted writes are skipped.
+    #[cfg(feature = "sqlite")]
+    pub(crate) fn read_all(dir: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        let mut values = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() || is_temp_file(&entry.file_name()) {
+                continue;
+            }
+            let key = entry
+                .file_name()
+                .into_string()
+                .or(Err(Error::from_str("invalid file name")))?;
+            values.push((key, fs::read(entry.path())?));
+        }
+        Ok(values)
     }
 
     /// Returns a readable `File` for the given file name.
@@ -38,13 +67,44 @@ impl FileStorage {
         Ok(file)
     }
 
-    /// Returns a writable `File` for the given file name.
+    /// Returns a writable, truncated `File` for the given file name that only its owner can access.
     fn file_for_write(&self, file: &str) -> Result<fs::File> {
         let file_path = self.path_to_file(file);
-        let file = fs::OpenOptions::new().write(true).create(true).open(file_path)?;
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(file_path)?;
         Ok(file)
     }
 
+    /// Writes a value to the given temporary file, syncs it to disk and renames it to the given file.
+    fn write_and_replace(&self, temp_file: &str, file: &str, value: &[u8]) -> Result<()> {
+        let mut temp = self.file_for_write(temp_file)?;
+        temp.write_all(value)?;
+        temp.sync_all()?;
+        fs::rename(self.path_to_file(temp_file), self.path_to_file(file))?;
+        Ok(())
+    }
+
+    /// Syncs the storage directory to disk, so that renamed or removed files persist.
+    fn sync_dir(&self) -> Result<()> {
+        fs::File::open(&self.dir_path)?.sync_all()?;
+        Ok(())
+    }
+
+    /// Removes temporary files left behind by writes that were interrupted, e.g. by a power loss.
+    fn remove_temp_files(&self) -> Result<()> {
+        for entry in fs::read_dir(&self.dir_path)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() && is_temp_file(&entry.file_name()) {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
     /// Returns the full storage path for the given file name.
     fn path_to_file(&self, file: &str) -> PathBuf {
         let mut file_path = self.dir_path.clone();
@@ -54,61 +114,21 @@ impl FileStorage {
 }
 
 impl Storage for FileStorage {
-    fn get_reader(&self, key: &str) -> Result<BufReader<fs::File>> {
-        let file = self.file_for_read(key)?;
-        let reader = BufReader::new(file);
-        Ok(reader)
-    }
-
-    fn get_writer(&self, key: &str) -> Result<BufWriter<fs::File>> {
-        let file = self.file_for_write(key)?;
-        let writer = BufWriter::new(file);
-        Ok(writer)
-    }
-
     fn get_bytes(&self, key: &str) -> Result<Vec<u8>> {
-        let mut reader = self.get_reader(key)?;
+        let mut file = self.file_for_read(key)?;
         let mut value = Vec::new();
-        reader.read_to_end(&mut value)?;
+        file.read_to_end(&mut value)?;
         Ok(value)
     }
 
     fn set_bytes(&self, key: &str, value: Vec<u8>) -> Result<()> {
-        let mut writer = self.get_writer(key)?;
-        writer.write_all(&value)?;
-        Ok(())
-    }
-
-    fn get_u64(&self, key: &str) -> Result<u64> {
-        let mut reader = self.get_reader(key)?;
-        let value = reader.read_u64::<BigEndian>()?;
-        Ok(value)
-    }
-
-    fn set_u64(&self, key: &str, value: u64) -> Result<()> {
-        let mut buf = [0; 8];
-        BigEndian::write_u64(&mut buf, value);
-        self.set_bytes(key, buf.to_vec())?;
-        Ok(())
-    }
-
-    fn get_uuid(&self, key: &str) -> Result<Uuid> {
-        let mut reader = self.get_reader(key)?;
-        let mut buf = Vec::new();
-        reader.read_to_end(&mut buf)?;
-        match str::from_utf8(&buf) {
-            Ok(uuid_str) => match Uuid::parse_str(uuid_str) {
-                Ok(value) => Ok(value),
-                _ => Err(Error::from_str("couldn't parse UUID")),
-            },
-            _ => Err(Error::from_str("couldn't read UUID")),
+        let temp_file = format!("{}{}{}", TEMP_FILE_PREFIX, key, TEMP_FILE_SUFFIX);
+        if let Err(e) = self.write_and_replace(&temp_file, key, &value) {
+            // the temporary file is of no use anymore, the previous value is still intact
+            let _ = fs::remove_file(self.path_to_file(&temp_file));
+            return Err(e);
         }
-    }
-
-    fn set_uuid(&self, key: &str, value: Uuid) -> Result<()> {
-        let mut writer = self.get_writer(key)?;
-        writer.write_all(value.to_hyphenated().to_string().as_bytes())?;
-        Ok(())
+        self.sync_dir()
     }
 
     fn keys_with_suffix(&self, suffix: &str) -> Result<Vec<String>> {
@@ -117,7 +137,8 @@ impl Storage for FileStorage {
         for entry in fs::read_dir(&self.dir_path)? {
             let entry = entry?;
             let path = entry.path();
-            if path.extension() == extension {
+            let is_temp = is_temp_file(&entry.file_name());
+            if path.extension() == extension && !is_temp {
                 let key = path
                     .file_stem()
                     .ok_or(Error::from_str("invalid file name"))?
@@ -133,6 +154,12 @@ impl Storage for FileStorage {
     fn delete(&self, key: &str) -> Result<()> {
         let file_path = self.path_to_file(key);
         fs::remove_file(file_path)?;
-        Ok(())
+        self.sync_dir()
     }
 }
+
+/// Returns whether a file name is the name of a temporary file.
+fn is_temp_file(name: &OsStr) -> bool {
+    let name = name.to_string_lossy();
+    name.starts_with(TEMP_FILE_PREFIX) && name.ends_with(TEMP_FILE_SUFFIX)
+}
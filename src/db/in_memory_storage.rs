@@ -0,0 +1,59 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use crate::db::storage::{not_found, strip_suffix, Storage};
+
+use crate::Result;
+
+/// `InMemoryStorage` is an implementor of the `Storage` trait that keeps data in memory, e.g. for
+/// tests. Clones of an `InMemoryStorage` share the same data.
+#[derive(Clone, Default)]
+pub struct InMemoryStorage {
+    values: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+}
+
+impl InMemoryStorage {
+    /// Creates a new, empty `InMemoryStorage`.
+    pub fn new() -> InMemoryStorage { InMemoryStorage::default() }
+}
+
+impl Storage for InMemoryStorage {
+    fn get_bytes(&self, key: &str) -> Result<Vec<u8>> {
+        self.values
+            .lock()
+            .expect("couldn't access values")
+            .get(key)
+            .cloned()
+            .ok_or_else(|| not_found(key))
+    }
+
+    fn set_bytes(&self, key: &str, value: Vec<u8>) -> Result<()> {
+        self.values
+            .lock()
+            .expect("couldn't access values")
+            .insert(key.to_string(), value);
+        Ok(())
+    }
+
+    fn keys_with_suffix(&self, suffix: &str) -> Result<Vec<String>> {
+        let keys = self
+            .values
+            .lock()
+            .expect("couldn't access values")
+            .keys()
+            .filter_map(|key| strip_suffix(key, suffix).map(String::from))
+            .collect();
+        Ok(keys)
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        self.values
+            .lock()
+            .expect("couldn't access values")
+            .remove(key)
+            .map(|_| ())
+            .ok_or_else(|| not_found(key))
+    }
+}
@@ -0,0 +1,45 @@
+use crate::Result;
+
+/// Records stored encrypted when a `KeyProvider` is set on a `Database`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum EncryptionScope {
+    /// Only the `Device`, which holds the accessory's private key and setup code.
+    Device,
+    /// The `Device` and all `Pairing`s.
+    DeviceAndPairings,
+}
+
+/// `KeyProvider` can be implemented to supply the key a `Database` encrypts its records with at
+/// rest, e.g. from a key file, an environment secret or a key derived from a passphrase.
+///
+/// # Examples
+///
+/// ```
+/// use hap::{db::KeyProvider, Error, Result};
+/// use std::env;
+///
+/// /// Reads the key as 64 hex digits from the environment variable `HAP_KEY`.
+/// struct EnvKey;
+///
+/// impl KeyProvider for EnvKey {
+///     fn key(&self) -> Result<[u8; 32]> {
+///         let hex = env::var("HAP_KEY").map_err(|_| Error::from_str("HAP_KEY isn't set"))?;
+///         let mut key = [0; 32];
+///         for (i, byte) in key.iter_mut().enumerate() {
+///             let digits = hex.get(i * 2..i * 2 + 2).ok_or(Error::from_str("HAP_KEY is too short"))?;
+///             *byte = u8::from_str_radix(digits, 16)?;
+///         }
+///         Ok(key)
+///     }
+/// }
+/// ```
+pub trait KeyProvider {
+    /// Returns the 32 byte key records are encrypted with using ChaCha20-Poly1305. It is called
+    /// every time an encrypted record is read or written. Records encrypted with one key can't be
+    /// read with another one.
+    fn key(&self) -> Result<[u8; 32]>;
+}
+
+impl KeyProvider for [u8; 32] {
+    fn key(&self) -> Result<[u8; 32]> { Ok(*self) }
+}
@@ -1,11 +1,23 @@
+mod access_policy;
 mod accessory_list;
 mod database;
 mod file_storage;
+mod in_memory_storage;
+mod key_provider;
+#[cfg(feature = "sqlite")]
+mod sqlite_storage;
 mod storage;
 
+#[cfg(feature = "sqlite")]
+pub use self::sqlite_storage::SqliteStorage;
 pub use self::{
+    access_policy::{AccessPolicy, Operation},
     accessory_list::{AccessoryList, AccessoryListMember, AccessoryListPtr},
-    database::{Database, DatabasePtr},
+    database::{Database, DatabasePtr, PairSetupAttempts},
     file_storage::FileStorage,
+    in_memory_storage::InMemoryStorage,
+    key_provider::{EncryptionScope, KeyProvider},
     storage::Storage,
 };
+
+pub(crate) use self::storage::is_not_found;
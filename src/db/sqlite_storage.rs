@@ -0,0 +1,116 @@
+use std::{
+    fs,
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    sync::{Arc, Mutex},
+};
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::db::{
+    file_storage::FileStorage,
+    storage::{not_found, strip_suffix, Storage},
+};
+
+use crate::Result;
+
+/// `SqliteStorage` is an implementor of the `Storage` trait that stores data to a single SQLite
+/// database file. Every write is a transaction, so a value is either fully written or not at all,
+/// even if the device loses power. Clones of a `SqliteStorage` share the same connection.
+///
+/// Requires the `sqlite` feature.
+#[derive(Clone)]
+pub struct SqliteStorage {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStorage {
+    /// Opens the SQLite database at the given file path, creating it if it doesn't exist yet. The file
+    /// is only accessible by its owner, as it holds the accessory's private key.
+    pub fn new(path: &str) -> Result<SqliteStorage> {
+        if path != ":memory:" {
+            fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .mode(0o600)
+                .open(path)?;
+            // the file may have been created with broader permissions before
+            fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        }
+        // SQLite creates the journal files with the permissions of the database file
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "PRAGMA journal_mode = WAL;
+            PRAGMA synchronous = FULL;
+            CREATE TABLE IF NOT EXISTS storage (key TEXT PRIMARY KEY NOT NULL, value BLOB NOT NULL);",
+        )?;
+        Ok(SqliteStorage {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Imports all values of the `FileStorage` at the given directory, e.g. the former
+    /// `Config::storage_path`, in a single transaction and returns the number of imported values.
+    /// Values already stored under the same key are replaced. The directory is left untouched.
+    pub fn import_file_storage(&self, dir: &str) -> Result<usize> {
+        let values = FileStorage::read_all(dir)?;
+
+        let mut connection = self.connection.lock().expect("couldn't access connection");
+        let transaction = connection.transaction()?;
+        for (key, value) in &values {
+            transaction.execute("INSERT OR REPLACE INTO storage (key, value) VALUES (?1, ?2)", params![
+                key, value
+            ])?;
+        }
+        transaction.commit()?;
+
+        Ok(values.len())
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn get_bytes(&self, key: &str) -> Result<Vec<u8>> {
+        self.connection
+            .lock()
+            .expect("couldn't access connection")
+            .query_row("SELECT value FROM storage WHERE key = ?1", params![key], |row| {
+                row.get(0)
+            })
+            .optional()?
+            .ok_or_else(|| not_found(key))
+    }
+
+    fn set_bytes(&self, key: &str, value: Vec<u8>) -> Result<()> {
+        self.connection
+            .lock()
+            .expect("couldn't access connection")
+            .execute("INSERT OR REPLACE INTO storage (key, value) VALUES (?1, ?2)", params![
+                key, value
+            ])?;
+        Ok(())
+    }
+
+    fn keys_with_suffix(&self, suffix: &str) -> Result<Vec<String>> {
+        let connection = self.connection.lock().expect("couldn't access connection");
+        let mut statement = connection.prepare("SELECT key FROM storage")?;
+        let mut keys = Vec::new();
+        for key in statement.query_map(params![], |row| row.get::<_, String>(0))? {
+            if let Some(stem) = strip_suffix(&key?, suffix) {
+                keys.push(stem.to_string());
+            }
+        }
+        Ok(keys)
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        let deleted = self
+            .connection
+            .lock()
+            .expect("couldn't access connection")
+            .execute("DELETE FROM storage WHERE key = ?1", params![key])?;
+        if deleted == 0 {
+            return Err(not_found(key));
+        }
+        Ok(())
+    }
+}
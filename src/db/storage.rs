@@ -1,33 +1,83 @@
-use std::{
-    fs::File,
-    io::{BufReader, BufWriter},
-};
+use std::{io, str};
 
+use byteorder::{BigEndian, ByteOrder};
 use uuid::Uuid;
 
-use crate::Result;
+use crate::{Error, ErrorKind, Result};
 
-/// `Storage` is implemented by the data storage methods HAP supports. Currently, that's just
-/// `FileStorage`.
+/// `Storage` is implemented by the data storage methods HAP supports. Currently, that's
+/// `FileStorage`, `InMemoryStorage` and, with the `sqlite` feature enabled, `SqliteStorage`.
+///
+/// Values are stored as byte buffers under string keys, so any key-value store can back a `Storage`.
+/// Only the byte buffer methods have to be implemented; values of other types are encoded to bytes.
 pub trait Storage {
-    /// Returns a `BufReader` to the `File` stored for the given key.
-    fn get_reader(&self, key: &str) -> Result<BufReader<File>>;
-    /// Returns a `BufWriter` to the `File` stored for the given key.
-    fn get_writer(&self, key: &str) -> Result<BufWriter<File>>;
     /// Returns the stored value for a given key as a `Vec<u8>`.
     fn get_bytes(&self, key: &str) -> Result<Vec<u8>>;
-    /// Stores a given `Vec<u8>` as the value for a given key.
+    /// Stores a given `Vec<u8>` as the value for a given key, replacing any previous value.
     fn set_bytes(&self, key: &str, value: Vec<u8>) -> Result<()>;
+    /// Returns all keys with a given suffix as a `Vec<String>`. The suffix is the part of a key
+    /// after its last `.`, and the returned keys don't contain it, e.g. the key `device.entity` is
+    /// returned as `device` for the suffix `entity`.
+    fn keys_with_suffix(&self, suffix: &str) -> Result<Vec<String>>;
+    /// Deletes the stored value for a given key.
+    fn delete(&self, key: &str) -> Result<()>;
+
     /// Returns the stored value for a given key as a `u64`.
-    fn get_u64(&self, key: &str) -> Result<u64>;
+    fn get_u64(&self, key: &str) -> Result<u64> {
+        let value = self.get_bytes(key)?;
+        if value.len() != 8 {
+            return Err(Error::from_str("couldn't read u64"));
+        }
+        Ok(BigEndian::read_u64(&value))
+    }
+
     /// Stores a given `u64` as the value for a given key.
-    fn set_u64(&self, key: &str, value: u64) -> Result<()>;
+    fn set_u64(&self, key: &str, value: u64) -> Result<()> {
+        let mut buf = [0; 8];
+        BigEndian::write_u64(&mut buf, value);
+        self.set_bytes(key, buf.to_vec())
+    }
+
     /// Returns the stored value for a given key as a `Uuid`.
-    fn get_uuid(&self, key: &str) -> Result<Uuid>;
+    fn get_uuid(&self, key: &str) -> Result<Uuid> {
+        let value = self.get_bytes(key)?;
+        match str::from_utf8(&value) {
+            Ok(uuid_str) => match Uuid::parse_str(uuid_str) {
+                Ok(value) => Ok(value),
+                _ => Err(Error::from_str("couldn't parse UUID")),
+            },
+            _ => Err(Error::from_str("couldn't read UUID")),
+        }
+    }
+
     /// Stores a given `Uuid` as the value for a given key.
-    fn set_uuid(&self, key: &str, value: Uuid) -> Result<()>;
-    /// Returns all keys with a given suffix as a `Vec<String>`.
-    fn keys_with_suffix(&self, suffix: &str) -> Result<Vec<String>>;
-    /// Deletes the stored value for a given key.
-    fn delete(&self, key: &str) -> Result<()>;
+    fn set_uuid(&self, key: &str, value: Uuid) -> Result<()> {
+        self.set_bytes(key, value.to_hyphenated().to_string().into_bytes())
+    }
+}
+
+/// Returns the part of a key before its last `.` if the part after it equals `suffix`.
+pub(crate) fn strip_suffix<'a>(key: &'a str, suffix: &str) -> Option<&'a str> {
+    let mut parts = key.rsplitn(2, '.');
+    match (parts.next(), parts.next()) {
+        (Some(extension), Some(stem)) if extension == suffix && !stem.is_empty() => Some(stem),
+        _ => None,
+    }
+}
+
+/// Returns the error for a key without a stored value.
+pub(crate) fn not_found(key: &str) -> Error {
+    ErrorKind::Io(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("no value stored for key {}", key),
+    ))
+    .into()
+}
+
+/// Returns whether an error is caused by a key without a stored value.
+pub(crate) fn is_not_found(err: &Error) -> bool {
+    match err.kind() {
+        ErrorKind::Io(e) => e.kind() == io::ErrorKind::NotFound,
+        _ => false,
+    }
 }
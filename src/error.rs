@@ -5,6 +5,8 @@ use eui48;
 use failure::{self, err_msg, Context, Fail};
 use hyper::{self, http};
 
+use crate::transport::Status;
+
 /// ErrorKind wrapper type.
 #[derive(Debug, Fail)]
 pub enum ErrorKind {
@@ -17,7 +19,7 @@ pub enum ErrorKind {
     #[fail(display = "HTTP Error {}", _0)]
     Http(#[cause] http::Error),
     #[fail(display = "Hyper Error {}", _0)]
-    Hyper(#[cause] hyper::error::Error),
+    Hyper(#[cause] hyper::Error),
     #[fail(display = "ChaCha20-Poly1305-AEAD Error {}", _0)]
     ChaCha20Poly1305Aead(#[cause] chacha20_poly1305_aead::DecryptError),
     #[fail(display = "UTF-8 Error {}", _0)]
@@ -28,6 +30,10 @@ pub enum ErrorKind {
     ParseInt(#[cause] num::ParseIntError),
     #[fail(display = "MPSC Send Error {}", _0)]
     MpscSend(#[cause] mpsc::SendError<()>),
+    #[fail(display = "Invalid Value {}", _0)]
+    InvalidValue(String),
+    #[fail(display = "HAP Status {:?}", _0)]
+    HapStatus(Status),
     #[fail(display = "Error {}", _0)]
     Other(failure::Error),
 }
@@ -95,8 +101,8 @@ impl From<http::Error> for Error {
     fn from(err: http::Error) -> Error { ErrorKind::Http(err).into() }
 }
 
-impl From<hyper::error::Error> for Error {
-    fn from(err: hyper::error::Error) -> Error { ErrorKind::Hyper(err).into() }
+impl From<hyper::Error> for Error {
+    fn from(err: hyper::Error) -> Error { ErrorKind::Hyper(err).into() }
 }
 
 impl From<chacha20_poly1305_aead::DecryptError> for Error {
@@ -118,3 +124,8 @@ impl From<num::ParseIntError> for Error {
 impl From<mpsc::SendError<()>> for Error {
     fn from(err: mpsc::SendError<()>) -> Error { ErrorKind::MpscSend(err).into() }
 }
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Error { ErrorKind::Other(err.into()).into() }
+}
@@ -2,24 +2,55 @@ use std::sync::{Arc, Mutex};
 
 use serde_json::Value;
 
+use crate::HapType;
+
 pub enum Event {
     DevicePaired,
     DeviceUnpaired,
-    CharacteristicValueChanged { aid: u64, iid: u64, value: Value },
+    /// The value of a Characteristic changed. `origin` is the connection that wrote the value, if it
+    /// was changed by a controller.
+    CharacteristicValueChanged {
+        aid: u64,
+        iid: u64,
+        hap_type: HapType,
+        value: Value,
+        origin: Option<ConnectionId>,
+    },
 }
 
+/// Identifies a single connection of a controller to the accessory server.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ConnectionId(pub u64);
+
+/// Handle to a listener added to an `EventEmitter`. It can be used to remove the listener again.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ListenerId(u64);
+
 #[derive(Default)]
 pub struct EventEmitter {
-    listeners: Vec<Box<dyn Fn(&Event) + Send>>,
+    listeners: Vec<(ListenerId, Box<dyn Fn(&Event) + Send>)>,
+    id_count: u64,
 }
 
 impl EventEmitter {
-    pub fn new() -> EventEmitter { EventEmitter { listeners: vec![] } }
+    pub fn new() -> EventEmitter {
+        EventEmitter {
+            listeners: vec![],
+            id_count: 0,
+        }
+    }
+
+    pub fn add_listener(&mut self, listener: Box<dyn Fn(&Event) + Send>) -> ListenerId {
+        let id = ListenerId(self.id_count);
+        self.id_count += 1;
+        self.listeners.push((id, listener));
+        id
+    }
 
-    pub fn add_listener(&mut self, listener: Box<dyn Fn(&Event) + Send>) { self.listeners.push(listener); }
+    pub fn remove_listener(&mut self, id: ListenerId) { self.listeners.retain(|&(l_id, _)| l_id != id); }
 
     pub fn emit(&self, event: &Event) {
-        for listener in &self.listeners {
+        for (_, listener) in &self.listeners {
             listener(&event);
         }
     }
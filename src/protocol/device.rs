@@ -10,7 +10,7 @@ use serde::{
 };
 
 use crate::{
-    db::{Database, DatabasePtr},
+    db::{is_not_found, Database, DatabasePtr},
     pin::Pin,
     Result,
 };
@@ -52,6 +52,8 @@ impl Device {
     pub fn load_or_new(id: String, pin: Pin, database: &Database) -> Result<Device> {
         match database.get_device() {
             Ok(device) => Ok(device),
+            // a Device that can't be read, e.g. without its encryption key, mustn't be replaced
+            Err(e) if !is_not_found(&e) => Err(e),
             Err(_) => {
                 let device = Device::new_random(id, pin);
                 database.set_device(&device)?;
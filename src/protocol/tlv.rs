@@ -1,4 +1,4 @@
-use std::{cell, collections::HashMap, io, str};
+use std::{cell, io, str};
 
 use byteorder::{LittleEndian, WriteBytesExt};
 use chacha20_poly1305_aead;
@@ -8,71 +8,146 @@ use uuid;
 
 use crate::{error, protocol::pairing::Permissions};
 
-/// Encodes a `HashMap<u8, Vec<u8>>` in the format `<Type, Value>` to a `Vec<u8>` of concatenated
-/// TLVs.
-pub fn encode(hm: HashMap<u8, Vec<u8>>) -> Vec<u8> {
+/// Encodes an ordered list of TLVs in the format `(Type, Value)` to a `Vec<u8>` of concatenated
+/// TLVs. Values longer than 255 bytes are split into fragments. Consecutive items of the same type
+/// have to be separated by a `Type::Separator` item, as they'd otherwise be decoded as one item.
+pub fn encode<I: IntoIterator<Item = (u8, Vec<u8>)>>(items: I) -> Vec<u8> {
     let mut vec: Vec<u8> = Vec::new();
-    for (k, v) in &hm {
-        let length = v.len();
-        if length <= 255 {
-            vec.push(*k);
-            vec.push(length as u8);
-            for byte in v {
-                vec.push(*byte);
-            }
-        } else {
-            let mut l = length;
-            let mut p = 0;
-            while l > 255 {
-                vec.push(*k);
-                vec.push(255);
-                for byte in &v[p..(p + 255)] {
-                    vec.push(*byte);
-                }
-                l -= 255;
-                p += 255;
-            }
-            if l > 0 {
-                vec.push(k.clone());
-                vec.push(l as u8);
-                for byte in &v[p..(p + l)] {
-                    vec.push(*byte);
-                }
-            }
+    for (t, v) in items {
+        if v.is_empty() {
+            vec.push(t);
+            vec.push(0);
+            continue;
+        }
+        for fragment in v.chunks(255) {
+            vec.push(t);
+            vec.push(fragment.len() as u8);
+            vec.extend_from_slice(fragment);
         }
     }
     vec
 }
 
-/// Decodes a `Vec<u8>` of concatenated TLVs to a `HashMap<u8, Vec<u8>>` in the format
-/// `<Type, Value>`.
-pub fn decode(tlv: Vec<u8>) -> HashMap<u8, Vec<u8>> {
-    let mut hm = HashMap::new();
-    let mut buf: Vec<u8> = Vec::new();
+/// Decodes a `Vec<u8>` of concatenated TLVs to an ordered list of TLVs. Fragments of a value
+/// longer than 255 bytes are joined again. Malformed input is rejected with a `DecodeError`.
+pub fn decode(tlv: Vec<u8>) -> Result<Items, DecodeError> {
+    let mut items: Vec<(u8, Vec<u8>)> = Vec::new();
+    // a fragment of 255 bytes is continued by a following fragment of the same type
+    let mut continued = false;
     let mut p = 0;
-    let mut pt = 0;
     while p < tlv.len() {
+        if tlv.len() - p < 2 {
+            return Err(DecodeError::TruncatedHeader { offset: p });
+        }
         let t = tlv[p];
-        let l = tlv[p + 1];
-        if l < 255 {
-            if t != pt && !buf.is_empty() {
-                hm.insert(t, buf.clone());
-                buf.clear();
-            }
-            buf.extend_from_slice(&tlv[p + 2..p + 2 + l as usize]);
-            hm.insert(t, buf.clone());
-            buf.clear();
-        } else {
-            buf.extend_from_slice(&tlv[p + 2..p + 2 + l as usize]);
+        let l = tlv[p + 1] as usize;
+        let remaining = tlv.len() - p - 2;
+        if l > remaining {
+            return Err(DecodeError::Overrun {
+                offset: p,
+                length: l,
+                remaining,
+            });
+        }
+        let value = &tlv[p + 2..p + 2 + l];
+        match items.last_mut() {
+            Some((last_t, last_v)) if continued && *last_t == t => {
+                if l == 0 {
+                    return Err(DecodeError::EmptyFragment { offset: p });
+                }
+                last_v.extend_from_slice(value);
+            },
+            _ => items.push((t, value.to_vec())),
         }
-        pt = t;
-        p = p + 2 + l as usize;
+        continued = l == 255;
+        p = p + 2 + l;
+    }
+    Ok(Items(items))
+}
+
+/// Errors that can occur when decoding malformed TLVs.
+#[derive(Copy, Clone, Debug, PartialEq, Fail)]
+pub enum DecodeError {
+    #[fail(display = "TLV header at offset {} is truncated", offset)]
+    TruncatedHeader { offset: usize },
+    #[fail(
+        display = "TLV at offset {} has a length of {} bytes, but only {} bytes are left",
+        offset, length, remaining
+    )]
+    Overrun {
+        offset: usize,
+        length: usize,
+        remaining: usize,
+    },
+    #[fail(
+        display = "TLV at offset {} continues a fragmented value with an empty fragment",
+        offset
+    )]
+    EmptyFragment { offset: usize },
+}
+
+/// Ordered list of TLVs in the format `(Type, Value)`. Unlike a map, it keeps items of the same
+/// type, e.g. the entries of a list separated by `Type::Separator`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Items(Vec<(u8, Vec<u8>)>);
+
+impl Items {
+    /// Creates a new, empty `Items`.
+    pub fn new() -> Items { Items(Vec::new()) }
+
+    /// Appends an item.
+    pub fn push(&mut self, t: u8, value: Vec<u8>) { self.0.push((t, value)); }
+
+    /// Returns the value of the first item of the given type.
+    pub fn get(&self, t: u8) -> Option<&Vec<u8>> { self.0.iter().find(|(i_t, _)| *i_t == t).map(|(_, v)| v) }
+
+    /// Returns the values of all items of the given type in order.
+    pub fn get_all(&self, t: u8) -> impl Iterator<Item = &Vec<u8>> {
+        self.0.iter().filter(move |(i_t, _)| *i_t == t).map(|(_, v)| v)
+    }
+
+    /// Removes the first item of the given type and returns its value.
+    pub fn remove(&mut self, t: u8) -> Option<Vec<u8>> {
+        let pos = self.0.iter().position(|(i_t, _)| *i_t == t)?;
+        Some(self.0.remove(pos).1)
     }
-    if !buf.is_empty() {
-        hm.insert(pt, buf.clone());
-        buf.clear();
+
+    /// Splits the items at every `Type::Separator` item, e.g. to get the entries of a list.
+    pub fn split(self) -> Vec<Items> {
+        let mut groups = vec![Items::new()];
+        for (t, v) in self.0 {
+            if t == Type::Separator as u8 {
+                groups.push(Items::new());
+            } else if let Some(group) = groups.last_mut() {
+                group.push(t, v);
+            }
+        }
+        groups
     }
-    hm
+
+    /// Returns an iterator over the items in the format `(Type, Value)`.
+    pub fn iter(&self) -> impl Iterator<Item = &(u8, Vec<u8>)> { self.0.iter() }
+
+    /// Returns the number of items.
+    pub fn len(&self) -> usize { self.0.len() }
+
+    /// Returns `true` if there are no items.
+    pub fn is_empty(&self) -> bool { self.0.is_empty() }
+}
+
+impl From<Vec<(u8, Vec<u8>)>> for Items {
+    fn from(items: Vec<(u8, Vec<u8>)>) -> Items { Items(items) }
+}
+
+impl IntoIterator for Items {
+    type IntoIter = std::vec::IntoIter<(u8, Vec<u8>)>;
+    type Item = (u8, Vec<u8>);
+
+    fn into_iter(self) -> Self::IntoIter { self.0.into_iter() }
+}
+
+impl Encodable for Items {
+    fn encode(self) -> Vec<u8> { encode(self.0) }
 }
 
 /// `Encodable` is implemented by types that can be encoded to a to a `Vec<u8>` of concatenated
@@ -98,6 +173,8 @@ pub enum Type {
     Permissions = 0x0B,
     FragmentData = 0x0C,
     FragmentLast = 0x0D,
+    SessionId = 0x0E,
+    Flags = 0x13,
     Separator = 0xFF,
 }
 
@@ -118,6 +195,8 @@ pub enum Value {
     Permissions(Permissions),
     FragmentData(Vec<u8>),
     FragmentLast(Vec<u8>),
+    SessionId(Vec<u8>),
+    Flags(u32),
     Separator,
 }
 
@@ -144,16 +223,19 @@ impl Value {
             Value::Permissions(permissions) => (Type::Permissions as u8, vec![permissions.as_u8()]),
             Value::FragmentData(fragment_data) => (Type::FragmentData as u8, fragment_data),
             Value::FragmentLast(fragment_last) => (Type::FragmentLast as u8, fragment_last),
-            Value::Separator => (Type::Separator as u8, vec![0x00]),
+            Value::SessionId(session_id) => (Type::SessionId as u8, session_id),
+            Value::Flags(flags) => {
+                let mut vec: Vec<u8> = Vec::new();
+                vec.write_u32::<LittleEndian>(flags).unwrap();
+                // flags are encoded with as few bytes as possible
+                while vec.len() > 1 && vec[vec.len() - 1] == 0 {
+                    vec.pop();
+                }
+                (Type::Flags as u8, vec)
+            },
+            Value::Separator => (Type::Separator as u8, vec![]),
         }
     }
-
-    /// Converts a variant of `Value` to a `(u8, Vec<u8>)` tuple in the format `(Type, Value)` and
-    /// inserts it into a `HashMap<u8, Vec<u8>>`.
-    pub fn into_map(self, map: &mut HashMap<u8, Vec<u8>>) {
-        let (t, v) = self.as_tlv();
-        map.insert(t, v);
-    }
 }
 
 #[allow(dead_code)]
@@ -164,6 +246,30 @@ pub enum Method {
     AddPairing = 3,
     RemovePairing = 4,
     ListPairings = 5,
+    PairResume = 6,
+}
+
+/// Pairing type flags, sent as `Type::Flags`.
+#[derive(Copy, Clone)]
+pub enum Flag {
+    /// Pair Setup only establishes a session without storing a pairing.
+    Transient = 0x0000_0010,
+    /// Pair Setup reuses the SRP verifier of a previous transient Pair Setup.
+    Split = 0x0100_0000,
+}
+
+impl Flag {
+    /// Decodes the little-endian value of a `Type::Flags` TLV.
+    pub fn decode(value: &[u8]) -> u32 {
+        value
+            .iter()
+            .take(4)
+            .enumerate()
+            .fold(0, |flags, (i, byte)| flags | (u32::from(*byte) << (8 * i)))
+    }
+
+    /// Returns whether the flag is set in `flags`.
+    pub fn is_set(self, flags: u32) -> bool { flags & self as u32 != 0 }
 }
 
 #[allow(dead_code)]
@@ -189,6 +295,10 @@ impl From<error::Error> for Error {
     fn from(_: error::Error) -> Self { Error::Unknown }
 }
 
+impl From<DecodeError> for Error {
+    fn from(_: DecodeError) -> Self { Error::Unknown }
+}
+
 impl From<io::Error> for Error {
     fn from(_: io::Error) -> Self { Error::Unknown }
 }
@@ -220,29 +330,132 @@ impl From<chacha20_poly1305_aead::DecryptError> for Error {
 pub type Container = Vec<Value>;
 
 impl Encodable for Container {
-    fn encode(self) -> Vec<u8> {
-        let mut map = HashMap::new();
-        for value in self {
-            value.into_map(&mut map);
-        }
-        encode(map)
-    }
+    fn encode(self) -> Vec<u8> { encode(self.into_iter().map(Value::as_tlv)) }
 }
 
 pub struct ErrorContainer {
     step: u8,
     error: Error,
+    retry_delay: Option<usize>,
 }
 
 impl ErrorContainer {
-    pub fn new(step: u8, error: Error) -> ErrorContainer { ErrorContainer { step, error } }
+    pub fn new(step: u8, error: Error) -> ErrorContainer {
+        ErrorContainer {
+            step,
+            error,
+            retry_delay: None,
+        }
+    }
+
+    /// Creates a new `ErrorContainer` with `Error::Backoff` and the number of seconds the client has
+    /// to wait before retrying.
+    pub fn backoff(step: u8, retry_delay: usize) -> ErrorContainer {
+        ErrorContainer {
+            step,
+            error: Error::Backoff,
+            retry_delay: Some(retry_delay),
+        }
+    }
+
+    /// Returns the `Error` the `ErrorContainer` is encoded with.
+    #[cfg(test)]
+    pub fn error(&self) -> Error { self.error }
 }
 
 impl Encodable for ErrorContainer {
     fn encode(self) -> Vec<u8> {
-        let mut map = HashMap::new();
-        Value::State(self.step).into_map(&mut map);
-        Value::Error(self.error).into_map(&mut map);
-        encode(map)
+        let mut container = vec![Value::State(self.step), Value::Error(self.error)];
+        if let Some(retry_delay) = self.retry_delay {
+            container.push(Value::RetryDelay(retry_delay));
+        }
+        container.encode()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip_fragmented_value() {
+        let value: Vec<u8> = (0..600).map(|i| i as u8).collect();
+        let encoded = encode(vec![(Type::PublicKey as u8, value.clone())]);
+
+        assert_eq!(encoded.len(), 600 + 3 * 2);
+        assert_eq!(&encoded[..2], &[Type::PublicKey as u8, 255]);
+        assert_eq!(&encoded[257..259], &[Type::PublicKey as u8, 255]);
+        assert_eq!(&encoded[514..516], &[Type::PublicKey as u8, 90]);
+
+        let decoded = decode(encoded).unwrap();
+        assert_eq!(decoded, Items::from(vec![(Type::PublicKey as u8, value)]));
+    }
+
+    #[test]
+    fn round_trip_separated_list() {
+        let first = uuid::Uuid::new_v4().to_hyphenated().to_string();
+        let second = uuid::Uuid::new_v4().to_hyphenated().to_string();
+        // the way the List Pairings response is encoded
+        let list: Container = vec![
+            Value::State(2),
+            Value::Identifier(first.clone()),
+            Value::PublicKey(vec![1; 32]),
+            Value::Permissions(Permissions::Admin),
+            Value::Separator,
+            Value::Identifier(second.clone()),
+            Value::PublicKey(vec![2; 32]),
+            Value::Permissions(Permissions::User),
+        ];
+
+        let decoded = decode(list.encode()).unwrap();
+        assert_eq!(decoded.get_all(Type::Identifier as u8).count(), 2);
+
+        let groups = decoded.split();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].get(Type::State as u8), Some(&vec![2]));
+        assert_eq!(groups[0].get(Type::Identifier as u8), Some(&first.into_bytes()));
+        assert_eq!(groups[0].get(Type::PublicKey as u8), Some(&vec![1; 32]));
+        assert_eq!(groups[0].get(Type::Permissions as u8), Some(&vec![1]));
+        assert_eq!(groups[1].get(Type::Identifier as u8), Some(&second.into_bytes()));
+        assert_eq!(groups[1].get(Type::PublicKey as u8), Some(&vec![2; 32]));
+        assert_eq!(groups[1].get(Type::Permissions as u8), Some(&vec![0]));
+    }
+
+    #[test]
+    fn decode_empty_input() {
+        assert_eq!(decode(vec![]), Ok(Items::new()));
+    }
+
+    #[test]
+    fn decode_truncated_header() {
+        assert_eq!(
+            decode(vec![Type::State as u8]),
+            Err(DecodeError::TruncatedHeader { offset: 0 })
+        );
+        assert_eq!(
+            decode(vec![Type::State as u8, 1, 1, Type::Method as u8]),
+            Err(DecodeError::TruncatedHeader { offset: 3 })
+        );
+    }
+
+    #[test]
+    fn decode_overrun() {
+        assert_eq!(
+            decode(vec![Type::PublicKey as u8, 5, 1, 2]),
+            Err(DecodeError::Overrun {
+                offset: 0,
+                length: 5,
+                remaining: 2,
+            })
+        );
+    }
+
+    #[test]
+    fn decode_empty_fragment() {
+        let mut tlv = vec![Type::PublicKey as u8, 255];
+        tlv.extend_from_slice(&[0; 255]);
+        tlv.extend_from_slice(&[Type::PublicKey as u8, 0]);
+
+        assert_eq!(decode(tlv), Err(DecodeError::EmptyFragment { offset: 257 }));
     }
 }
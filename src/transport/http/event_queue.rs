@@ -0,0 +1,167 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{
+    channel::mpsc::{UnboundedReceiver, UnboundedSender},
+    Stream,
+};
+use log::error;
+use tokio::time::{sleep_until, Instant, Sleep};
+
+use crate::{
+    transport::http::{event_response, EventObject},
+    HapType,
+};
+
+/// Per-connection queue batching the `EventObject`s of a connection into `EVENT/1.0` messages.
+///
+/// Events received within `batch_window` are sent as a single message. If a characteristic changes
+/// multiple times before its event is sent, only the latest value is sent. Events for the same
+/// characteristic are sent at most once per `rate_limit`. Events of characteristics that report
+/// single occurrences, like button presses, are exempt from this: every one of them is sent right
+/// away. The queue finishes as soon as either the sending side of the queue or the outgoing stream of
+/// the connection is closed.
+pub struct EventQueue {
+    receiver: UnboundedReceiver<EventObject>,
+    outgoing_sender: UnboundedSender<Vec<u8>>,
+    batch_window: Duration,
+    rate_limit: Duration,
+    pending: Vec<EventObject>,
+    last_sent: HashMap<(u64, u64), Instant>,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl EventQueue {
+    pub fn new(
+        receiver: UnboundedReceiver<EventObject>,
+        outgoing_sender: UnboundedSender<Vec<u8>>,
+        batch_window: Duration,
+        rate_limit: Duration,
+    ) -> EventQueue {
+        EventQueue {
+            receiver,
+            outgoing_sender,
+            batch_window,
+            rate_limit,
+            pending: Vec::new(),
+            last_sent: HashMap::new(),
+            delay: None,
+        }
+    }
+
+    /// Queues an event, or sends it right away if it mustn't be delayed. Returns `false` if the
+    /// connection is closed.
+    fn push(&mut self, event: EventObject) -> bool {
+        if is_immediate(event.hap_type) {
+            return self.send(vec![event]);
+        }
+
+        match self
+            .pending
+            .iter_mut()
+            .find(|e| e.aid == event.aid && e.iid == event.iid)
+        {
+            Some(pending_event) => pending_event.value = event.value,
+            None => self.pending.push(event),
+        }
+        self.schedule(Instant::now() + self.batch_window);
+        true
+    }
+
+    /// Makes sure the queue is flushed no later than `deadline`.
+    fn schedule(&mut self, deadline: Instant) {
+        match self.delay {
+            Some(ref mut delay) =>
+                if delay.deadline() > deadline {
+                    delay.as_mut().reset(deadline);
+                },
+            None => self.delay = Some(Box::pin(sleep_until(deadline))),
+        }
+    }
+
+    /// Sends all pending events that aren't held back by the rate limit in one message. Returns
+    /// `false` if the connection is closed.
+    fn flush(&mut self) -> bool {
+        let now = Instant::now();
+        let rate_limit = self.rate_limit;
+        let last_sent = &self.last_sent;
+        let (ready, held): (Vec<EventObject>, Vec<EventObject>) = self.pending.drain(..).partition(|e| match last_sent
+            .get(&(e.aid, e.iid))
+        {
+            Some(&sent) => sent + rate_limit <= now,
+            None => true,
+        });
+        self.pending = held;
+
+        if let Some(next) = self
+            .pending
+            .iter()
+            .filter_map(|e| self.last_sent.get(&(e.aid, e.iid)))
+            .min()
+            .map(|&sent| sent + rate_limit)
+        {
+            self.schedule(next);
+        }
+
+        if ready.is_empty() {
+            return true;
+        }
+        for e in &ready {
+            self.last_sent.insert((e.aid, e.iid), now);
+        }
+        self.send(ready)
+    }
+
+    /// Sends events in one message. Returns `false` if the connection is closed.
+    fn send(&self, events: Vec<EventObject>) -> bool {
+        match event_response(events) {
+            Ok(event_res) => self.outgoing_sender.unbounded_send(event_res).is_ok(),
+            Err(e) => {
+                error!("couldn't create event response: {}", e);
+                true
+            },
+        }
+    }
+}
+
+impl Future for EventQueue {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        loop {
+            match Pin::new(&mut self.receiver).poll_next(cx) {
+                Poll::Ready(Some(event)) =>
+                    if !self.push(event) {
+                        return Poll::Ready(());
+                    },
+                Poll::Ready(None) => return Poll::Ready(()),
+                Poll::Pending => break,
+            }
+        }
+
+        loop {
+            let elapsed = match self.delay {
+                Some(ref mut delay) => delay.as_mut().poll(cx).is_ready(),
+                None => false,
+            };
+            if !elapsed {
+                return Poll::Pending;
+            }
+            self.delay = None;
+            if !self.flush() {
+                return Poll::Ready(());
+            }
+        }
+    }
+}
+
+/// Returns whether events of a characteristic are sent immediately instead of being batched,
+/// coalesced and rate limited. Each of these events stands for a single occurrence, e.g. a single or
+/// double press of a programmable switch, so every one of them has to reach the controller without
+/// delay.
+fn is_immediate(hap_type: HapType) -> bool { matches!(hap_type, HapType::ProgrammableSwitchEvent) }
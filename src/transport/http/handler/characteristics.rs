@@ -1,22 +1,24 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Duration};
 
+use futures::future::{self, BoxFuture};
 use hyper::{Body, Response, StatusCode, Uri};
+use tokio::time::timeout;
 use url::form_urlencoded;
 
 use crate::{
     config::ConfigPtr,
     db::{AccessoryList, DatabasePtr},
-    event::EventEmitterPtr,
+    event::{ConnectionId, EventEmitterPtr},
     protocol::IdPtr,
     transport::http::{
-        handler::JsonHandler,
+        handler::{prepare::PreparedWritePtr, AsyncJsonHandler},
         json_response,
         server::EventSubscriptions,
         status_response,
         CharacteristicResponseBody,
         ReadResponseObject,
         Status,
-        WriteObject,
+        WriteRequestBody,
         WriteResponseObject,
     },
     Error,
@@ -30,63 +32,104 @@ impl GetCharacteristics {
     pub fn new() -> GetCharacteristics { GetCharacteristics }
 }
 
-impl JsonHandler for GetCharacteristics {
+impl AsyncJsonHandler for GetCharacteristics {
     fn handle(
         &mut self,
         uri: Uri,
         _: Vec<u8>,
-        _: &IdPtr,
-        _: &EventSubscriptions,
-        _: &ConfigPtr,
-        _: &DatabasePtr,
+        controller_id: &IdPtr,
+        event_subscriptions: &EventSubscriptions,
+        config: &ConfigPtr,
+        database: &DatabasePtr,
         accessories: &AccessoryList,
         _: &EventEmitterPtr,
-    ) -> Result<Response<Body>> {
-        if let Some(query) = uri.query() {
-            let mut resp_body = CharacteristicResponseBody::<ReadResponseObject> {
-                characteristics: Vec::new(),
-            };
-            let mut some_err = false;
-
-            // TODO - using a String seems ugly
-            let mut queries: HashMap<String, String> = HashMap::new();
-            for (key, val) in form_urlencoded::parse(query.as_bytes()) {
-                queries.insert(key.into(), val.into());
-            }
-            let (f_meta, f_perms, f_type, f_ev) = check_flags(&queries);
-            let q_id = queries
-                .get("id")
-                .ok_or(Error::new(ErrorKind::HttpStatus(StatusCode::BAD_REQUEST)))?;
-            let ids = q_id.split(',').collect::<Vec<&str>>();
-            for id in ids {
-                let id_pair = id.split('.').collect::<Vec<&str>>();
-                if id_pair.len() != 2 {
-                    return Err(ErrorKind::HttpStatus(StatusCode::BAD_REQUEST).into());
-                }
-                let aid = id_pair[0].parse::<u64>()?;
-                let iid = id_pair[1].parse::<u64>()?;
+    ) -> BoxFuture<'static, Result<Response<Body>>> {
+        let characteristic_timeout = config.lock().expect("couldn't access config").characteristic_timeout;
+        match read_characteristics(
+            uri,
+            characteristic_timeout,
+            controller_id,
+            event_subscriptions,
+            database,
+            accessories,
+        ) {
+            Ok(res) => res,
+            Err(e) => Box::pin(future::err(e)),
+        }
+    }
+}
 
-                let res_object = match accessories.read_characteristic(aid, iid, f_meta, f_perms, f_type, f_ev) {
-                    Ok(mut res_object) => {
+fn read_characteristics(
+    uri: Uri,
+    characteristic_timeout: Duration,
+    controller_id: &IdPtr,
+    event_subscriptions: &EventSubscriptions,
+    database: &DatabasePtr,
+    accessories: &AccessoryList,
+) -> Result<BoxFuture<'static, Result<Response<Body>>>> {
+    if let Some(query) = uri.query() {
+        // TODO - using a String seems ugly
+        let mut queries: HashMap<String, String> = HashMap::new();
+        for (key, val) in form_urlencoded::parse(query.as_bytes()) {
+            queries.insert(key.into(), val.into());
+        }
+        let (f_meta, f_perms, f_type, f_ev) = check_flags(&queries);
+        let q_id = queries
+            .get("id")
+            .ok_or(Error::new(ErrorKind::HttpStatus(StatusCode::BAD_REQUEST)))?;
+        let ids = q_id.split(',').collect::<Vec<&str>>();
+
+        let mut reads = Vec::new();
+        for id in ids {
+            let id_pair = id.split('.').collect::<Vec<&str>>();
+            if id_pair.len() != 2 {
+                return Err(ErrorKind::HttpStatus(StatusCode::BAD_REQUEST).into());
+            }
+            let aid = id_pair[0].parse::<u64>()?;
+            let iid = id_pair[1].parse::<u64>()?;
+
+            let read = timeout(
+                characteristic_timeout,
+                accessories.read_characteristic(
+                    aid,
+                    iid,
+                    f_meta,
+                    f_perms,
+                    f_type,
+                    f_ev,
+                    controller_id,
+                    database,
+                    event_subscriptions,
+                ),
+            );
+            reads.push(async move {
+                match read.await {
+                    Ok(Ok(mut res_object)) => {
                         if res_object.status != Some(0) {
-                            some_err = true;
                             res_object.value = None;
                         }
                         res_object
                     },
-                    Err(_) => {
-                        some_err = true;
-                        ReadResponseObject {
-                            iid,
-                            aid,
-                            status: Some(Status::ServiceCommunicationFailure as i32),
-                            ..Default::default()
-                        }
+                    Ok(Err(_)) => ReadResponseObject {
+                        iid,
+                        aid,
+                        status: Some(Status::ServiceCommunicationFailure as i32),
+                        ..Default::default()
+                    },
+                    Err(_) => ReadResponseObject {
+                        iid,
+                        aid,
+                        status: Some(Status::OperationTimedOut as i32),
+                        ..Default::default()
                     },
-                };
+                }
+            });
+        }
 
-                resp_body.characteristics.push(res_object);
-            }
+        Ok(Box::pin(async move {
+            let characteristics = future::join_all(reads).await;
+            let some_err = characteristics.iter().any(|r| r.status != Some(0));
+            let mut resp_body = CharacteristicResponseBody::<ReadResponseObject> { characteristics };
 
             if some_err {
                 let res = serde_json::to_vec(&resp_body)?;
@@ -98,9 +141,9 @@ impl JsonHandler for GetCharacteristics {
             let res = serde_json::to_vec(&resp_body)?;
 
             json_response(res, StatusCode::OK)
-        } else {
-            status_response(StatusCode::BAD_REQUEST)
-        }
+        }))
+    } else {
+        Ok(Box::pin(future::ready(status_response(StatusCode::BAD_REQUEST))))
     }
 }
 
@@ -114,64 +157,98 @@ fn check_flags(flags: &HashMap<String, String>) -> (bool, bool, bool, bool) {
     )
 }
 
-pub struct UpdateCharacteristics {}
+pub struct UpdateCharacteristics {
+    prepared_write: PreparedWritePtr,
+    connection_id: ConnectionId,
+}
 
 impl UpdateCharacteristics {
-    pub fn new() -> UpdateCharacteristics { UpdateCharacteristics {} }
+    pub fn new(prepared_write: PreparedWritePtr, connection_id: ConnectionId) -> UpdateCharacteristics {
+        UpdateCharacteristics {
+            prepared_write,
+            connection_id,
+        }
+    }
 }
 
-impl JsonHandler for UpdateCharacteristics {
+impl AsyncJsonHandler for UpdateCharacteristics {
     fn handle(
         &mut self,
         _: Uri,
         body: Vec<u8>,
-        _: &IdPtr,
+        controller_id: &IdPtr,
         event_subscriptions: &EventSubscriptions,
-        _: &ConfigPtr,
-        _: &DatabasePtr,
+        config: &ConfigPtr,
+        database: &DatabasePtr,
         accessories: &AccessoryList,
         _: &EventEmitterPtr,
-    ) -> Result<Response<Body>> {
-        let write_body: CharacteristicResponseBody<WriteObject> = serde_json::from_slice(&body)?;
-        let mut resp_body = CharacteristicResponseBody::<WriteResponseObject> {
-            characteristics: Vec::new(),
+    ) -> BoxFuture<'static, Result<Response<Body>>> {
+        let write_body: WriteRequestBody = match serde_json::from_slice(&body) {
+            Ok(write_body) => write_body,
+            Err(e) => return Box::pin(future::err(e.into())),
+        };
+        let characteristic_timeout = config.lock().expect("couldn't access config").characteristic_timeout;
+        // a prepared Timed Write is only valid for a single write request
+        let timed_write = match write_body.pid {
+            Some(pid) => match self
+                .prepared_write
+                .lock()
+                .expect("couldn't access prepared_write")
+                .take()
+            {
+                Some(prepared_write) => prepared_write.is_valid_for(pid),
+                None => false,
+            },
+            None => false,
         };
-        let mut some_err = false;
-        let mut all_err = true;
 
+        let mut writes = Vec::new();
         for c in write_body.characteristics {
             let iid = c.iid;
             let aid = c.aid;
-            let res_object = match accessories.write_characteristic(c, event_subscriptions) {
-                Ok(res_object) => {
-                    if res_object.status != 0 {
-                        some_err = true;
-                    } else {
-                        all_err = false;
-                    }
-                    res_object
-                },
-                Err(_) => {
-                    some_err = true;
-                    WriteResponseObject {
+            let write = timeout(
+                characteristic_timeout,
+                accessories.write_characteristic(
+                    c,
+                    timed_write,
+                    controller_id,
+                    database,
+                    event_subscriptions,
+                    self.connection_id,
+                ),
+            );
+            writes.push(async move {
+                match write.await {
+                    Ok(Ok(res_object)) => res_object,
+                    Ok(Err(_)) => WriteResponseObject {
                         iid,
                         aid,
                         status: Status::ServiceCommunicationFailure as i32,
-                    }
-                },
-            };
-
-            resp_body.characteristics.push(res_object);
+                    },
+                    Err(_) => WriteResponseObject {
+                        iid,
+                        aid,
+                        status: Status::OperationTimedOut as i32,
+                    },
+                }
+            });
         }
 
-        if all_err {
-            let res = serde_json::to_vec(&resp_body)?;
-            json_response(res, StatusCode::BAD_REQUEST)
-        } else if some_err {
-            let res = serde_json::to_vec(&resp_body)?;
-            json_response(res, StatusCode::MULTI_STATUS)
-        } else {
-            status_response(StatusCode::NO_CONTENT)
-        }
+        Box::pin(async move {
+            let characteristics = future::join_all(writes).await;
+            let some_err = characteristics.iter().any(|r| r.status != 0);
+            let all_err = characteristics.iter().all(|r| r.status != 0);
+            let resp_body = CharacteristicResponseBody::<WriteResponseObject> { characteristics };
+
+            if all_err {
+                let res = serde_json::to_vec(&resp_body)?;
+                json_response(res, StatusCode::BAD_REQUEST)
+            } else if some_err {
+                let res = serde_json::to_vec(&resp_body)?;
+                json_response(res, StatusCode::MULTI_STATUS)
+            } else {
+                status_response(StatusCode::NO_CONTENT)
+            }
+        })
     }
 }
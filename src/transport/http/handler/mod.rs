@@ -1,5 +1,8 @@
-use futures::{future, Future};
-use hyper::{self, Body, Response, StatusCode, Uri};
+use futures::{
+    future::{self, BoxFuture},
+    FutureExt,
+};
+use hyper::{Body, Response, StatusCode, Uri};
 
 use crate::{
     config::ConfigPtr,
@@ -10,7 +13,6 @@ use crate::{
         IdPtr,
     },
     transport::http::{server::EventSubscriptions, status_response, tlv_response},
-    Error,
     ErrorKind,
     Result,
 };
@@ -21,6 +23,7 @@ pub mod identify;
 pub mod pair_setup;
 pub mod pair_verify;
 pub mod pairings;
+pub mod prepare;
 
 pub trait Handler {
     fn handle(
@@ -33,7 +36,7 @@ pub trait Handler {
         database: &DatabasePtr,
         accessories: &AccessoryList,
         event_emitter: &EventEmitterPtr,
-    ) -> Box<dyn Future<Item = Response<Body>, Error = Error> + Send>;
+    ) -> BoxFuture<'static, Result<Response<Body>>>;
 }
 
 pub trait TlvHandler {
@@ -67,7 +70,7 @@ impl<T: TlvHandler> Handler for TlvHandlerType<T> {
         database: &DatabasePtr,
         _: &AccessoryList,
         event_emitter: &EventEmitterPtr,
-    ) -> Box<dyn Future<Item = Response<Body>, Error = Error> + Send> {
+    ) -> BoxFuture<'static, Result<Response<Body>>> {
         let response = match self.0.parse(body) {
             Err(e) => e.encode(),
             Ok(step) => match self.0.handle(step, controller_id, config, database, event_emitter) {
@@ -75,9 +78,7 @@ impl<T: TlvHandler> Handler for TlvHandlerType<T> {
                 Ok(res) => res.encode(),
             },
         };
-        Box::new(future::result(
-            tlv_response(response, StatusCode::OK).map_err(Error::from),
-        ))
+        Box::pin(future::ready(tlv_response(response, StatusCode::OK)))
     }
 }
 
@@ -112,7 +113,7 @@ impl<T: JsonHandler> Handler for JsonHandlerType<T> {
         database: &DatabasePtr,
         accessory_list: &AccessoryList,
         event_emitter: &EventEmitterPtr,
-    ) -> Box<dyn Future<Item = Response<Body>, Error = Error> + Send> {
+    ) -> BoxFuture<'static, Result<Response<Body>>> {
         let response = match self.0.handle(
             uri,
             body,
@@ -129,6 +130,74 @@ impl<T: JsonHandler> Handler for JsonHandlerType<T> {
                 _ => status_response(StatusCode::INTERNAL_SERVER_ERROR),
             },
         };
-        Box::new(future::result(response))
+        Box::pin(future::ready(response))
+    }
+}
+
+pub trait AsyncJsonHandler {
+    fn handle(
+        &mut self,
+        uri: Uri,
+        body: Vec<u8>,
+        controller_id: &IdPtr,
+        event_subscriptions: &EventSubscriptions,
+        config: &ConfigPtr,
+        database: &DatabasePtr,
+        accessory_list: &AccessoryList,
+        event_emitter: &EventEmitterPtr,
+    ) -> BoxFuture<'static, Result<Response<Body>>>;
+}
+
+pub struct AsyncJsonHandlerType<T: AsyncJsonHandler>(T);
+
+impl<T: AsyncJsonHandler> From<T> for AsyncJsonHandlerType<T> {
+    fn from(inst: T) -> AsyncJsonHandlerType<T> { AsyncJsonHandlerType(inst) }
+}
+
+impl<T: AsyncJsonHandler> Handler for AsyncJsonHandlerType<T> {
+    fn handle(
+        &mut self,
+        uri: Uri,
+        body: Vec<u8>,
+        controller_id: &IdPtr,
+        event_subscriptions: &EventSubscriptions,
+        config: &ConfigPtr,
+        database: &DatabasePtr,
+        accessory_list: &AccessoryList,
+        event_emitter: &EventEmitterPtr,
+    ) -> BoxFuture<'static, Result<Response<Body>>> {
+        Box::pin(
+            self.0
+                .handle(
+                    uri,
+                    body,
+                    controller_id,
+                    event_subscriptions,
+                    config,
+                    database,
+                    accessory_list,
+                    event_emitter,
+                )
+                .map(|res| match res {
+                    Ok(res) => Ok(res),
+                    Err(e) => match e.kind() {
+                        &ErrorKind::HttpStatus(status) => status_response(status),
+                        _ => status_response(StatusCode::INTERNAL_SERVER_ERROR),
+                    },
+                }),
+        )
+    }
+}
+
+/// Feeds every truncation of `body` to the handler, which mustn't panic on any of them, and checks
+/// that the one cut at `len` is rejected with `tlv::Error::Unknown`.
+#[cfg(test)]
+pub fn assert_rejects_truncated_body<H: TlvHandler>(handler: &H, body: &[u8], len: usize) {
+    for l in 0..body.len() {
+        let _ = handler.parse(body[..l].to_vec());
+    }
+    match handler.parse(body[..len].to_vec()) {
+        Ok(_) => panic!("truncated body was parsed"),
+        Err(e) => assert_eq!(e.error() as u8, tlv::Error::Unknown as u8),
     }
 }
@@ -1,4 +1,10 @@
-use std::{collections::HashMap, ops::BitXor, str};
+use std::{
+    cmp::min,
+    ops::BitXor,
+    str,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
 
 use chacha20_poly1305_aead;
 use crypto::ed25519;
@@ -17,16 +23,16 @@ use uuid::Uuid;
 
 use crate::{
     config::ConfigPtr,
-    db::DatabasePtr,
-    event::{Event, EventEmitterPtr},
+    db::{DatabasePtr, PairSetupAttempts},
+    event::{ConnectionId, Event, EventEmitterPtr},
     protocol::{
-        tlv::{self, Type, Value},
+        tlv::{self, Encodable, Flag, Type, Value},
         Device,
         IdPtr,
         Pairing,
         Permissions,
     },
-    transport::http::handler::TlvHandler,
+    transport::{http::handler::TlvHandler, tcp},
 };
 
 struct Session {
@@ -34,23 +40,88 @@ struct Session {
     verifier: Vec<u8>,
     b: Vec<u8>,
     b_pub: Vec<u8>,
+    flags: u32,
     shared_secret: Option<Vec<u8>>,
 }
 
+/// SRP salt and verifier of a transient Pair Setup with the `Split` flag, which are reused by a
+/// following Pair Setup with the `Split` flag.
+#[derive(Clone)]
+pub struct SplitVerifier {
+    salt: Vec<u8>,
+    verifier: Vec<u8>,
+}
+
+pub type SplitVerifierPtr = Arc<Mutex<Option<SplitVerifier>>>;
+
+/// Server-wide lock allowing only one Pair Setup at a time. Holds the connection running Pair Setup
+/// and the time it was started.
+pub type PairSetupLockPtr = Arc<Mutex<Option<(ConnectionId, Instant)>>>;
+
+/// Time after which an unfinished Pair Setup no longer blocks other connections.
+const PAIR_SETUP_TIMEOUT: Duration = Duration::from_secs(30);
+
 pub struct PairSetup {
     session: Option<Session>,
-    unsuccessful_tries: u8,
+    session_sender: tcp::SessionSenderPtr,
+    split_verifier: SplitVerifierPtr,
+    pair_setup_lock: PairSetupLockPtr,
+    connection_id: ConnectionId,
 }
 
 impl PairSetup {
-    pub fn new() -> PairSetup {
+    pub fn new(
+        session_sender: tcp::SessionSenderPtr,
+        split_verifier: SplitVerifierPtr,
+        pair_setup_lock: PairSetupLockPtr,
+        connection_id: ConnectionId,
+    ) -> PairSetup {
         PairSetup {
             session: None,
-            unsuccessful_tries: 0,
+            session_sender,
+            split_verifier,
+            pair_setup_lock,
+            connection_id,
+        }
+    }
+
+    /// Takes the Pair Setup lock for this connection. Returns `false` if another connection is
+    /// running Pair Setup and hasn't timed out yet.
+    fn acquire_lock(&self) -> bool {
+        let mut lock = self.pair_setup_lock.lock().expect("couldn't access pair setup lock");
+        match *lock {
+            Some((id, started_at)) if id != self.connection_id && started_at.elapsed() < PAIR_SETUP_TIMEOUT => false,
+            _ => {
+                *lock = Some((self.connection_id, Instant::now()));
+                true
+            },
+        }
+    }
+
+    /// Returns whether another connection has taken the Pair Setup lock.
+    fn is_locked_by_other(&self) -> bool {
+        match *self.pair_setup_lock.lock().expect("couldn't access pair setup lock") {
+            Some((id, _)) => id != self.connection_id,
+            None => false,
+        }
+    }
+
+    /// Ends the Pair Setup of this connection and releases the lock if this connection holds it.
+    fn release_lock(&mut self) {
+        self.session = None;
+        let mut lock = self.pair_setup_lock.lock().expect("couldn't access pair setup lock");
+        if let Some((id, _)) = *lock {
+            if id == self.connection_id {
+                *lock = None;
+            }
         }
     }
 }
 
+impl Drop for PairSetup {
+    fn drop(&mut self) { self.release_lock(); }
+}
+
 enum StepNumber {
     Unknown = 0,
     StartReq = 1,
@@ -62,7 +133,7 @@ enum StepNumber {
 }
 
 pub enum Step {
-    Start,
+    Start { flags: u32 },
     Verify { a_pub: Vec<u8>, a_proof: Vec<u8> },
     Exchange { data: Vec<u8> },
 }
@@ -72,18 +143,22 @@ impl TlvHandler for PairSetup {
     type Result = tlv::Container;
 
     fn parse(&self, body: Vec<u8>) -> Result<Step, tlv::ErrorContainer> {
-        let mut decoded = tlv::decode(body);
-        match decoded.get(&(Type::State as u8)) {
-            Some(method) => match method[0] {
-                x if x == StepNumber::StartReq as u8 => Ok(Step::Start),
+        let mut decoded = tlv::decode(body).map_err(|e| {
+            debug!("Couldn't decode TLVs: {}", e);
+            tlv::ErrorContainer::new(StepNumber::Unknown as u8, tlv::Error::Unknown)
+        })?;
+        match decoded.get(Type::State as u8).and_then(|s| s.first()) {
+            Some(&method) => match method {
+                x if x == StepNumber::StartReq as u8 => {
+                    let flags = decoded.get(Type::Flags as u8).map(|f| Flag::decode(f)).unwrap_or(0);
+                    Ok(Step::Start { flags })
+                },
                 x if x == StepNumber::VerifyReq as u8 => {
-                    let a_pub = decoded
-                        .remove(&(Type::PublicKey as u8))
-                        .ok_or(tlv::ErrorContainer::new(
-                            StepNumber::VerifyRes as u8,
-                            tlv::Error::Unknown,
-                        ))?;
-                    let a_proof = decoded.remove(&(Type::Proof as u8)).ok_or(tlv::ErrorContainer::new(
+                    let a_pub = decoded.remove(Type::PublicKey as u8).ok_or(tlv::ErrorContainer::new(
+                        StepNumber::VerifyRes as u8,
+                        tlv::Error::Unknown,
+                    ))?;
+                    let a_proof = decoded.remove(Type::Proof as u8).ok_or(tlv::ErrorContainer::new(
                         StepNumber::VerifyRes as u8,
                         tlv::Error::Unknown,
                     ))?;
@@ -91,7 +166,7 @@ impl TlvHandler for PairSetup {
                 },
                 x if x == StepNumber::ExchangeReq as u8 => {
                     let data = decoded
-                        .remove(&(Type::EncryptedData as u8))
+                        .remove(Type::EncryptedData as u8)
                         .ok_or(tlv::ErrorContainer::new(
                             StepNumber::ExchangeRes as u8,
                             tlv::Error::Unknown,
@@ -113,56 +188,151 @@ impl TlvHandler for PairSetup {
         event_emitter: &EventEmitterPtr,
     ) -> Result<tlv::Container, tlv::ErrorContainer> {
         match step {
-            Step::Start => match handle_start(self, database) {
-                Ok(res) => {
-                    self.unsuccessful_tries = 0;
-                    Ok(res)
-                },
-                Err(err) => {
-                    self.unsuccessful_tries += 1;
-                    Err(tlv::ErrorContainer::new(StepNumber::StartRes as u8, err))
-                },
+            Step::Start { flags } => {
+                // an accessory that's already paired has to be reset before it can be paired again
+                let pairing_count = database
+                    .lock()
+                    .expect("couldn't access database")
+                    .count_pairings()
+                    .map_err(|_| tlv::ErrorContainer::new(StepNumber::StartRes as u8, tlv::Error::Unknown))?;
+                if pairing_count > 0 {
+                    return Err(tlv::ErrorContainer::new(
+                        StepNumber::StartRes as u8,
+                        tlv::Error::Unavailable,
+                    ));
+                }
+                check_attempts(database)?;
+                if !self.acquire_lock() {
+                    return Err(tlv::ErrorContainer::new(StepNumber::StartRes as u8, tlv::Error::Busy));
+                }
+                match handle_start(self, database, flags) {
+                    Ok(res) => Ok(res),
+                    Err(err) => {
+                        self.release_lock();
+                        Err(tlv::ErrorContainer::new(StepNumber::StartRes as u8, err))
+                    },
+                }
+            },
+            Step::Verify { .. } | Step::Exchange { .. } if self.is_locked_by_other() => {
+                // the Pair Setup of this connection timed out and another one was started
+                self.session = None;
+                let step_number = match step {
+                    Step::Verify { .. } => StepNumber::VerifyRes,
+                    _ => StepNumber::ExchangeRes,
+                };
+                Err(tlv::ErrorContainer::new(step_number as u8, tlv::Error::Busy))
             },
             Step::Verify { a_pub, a_proof } => match handle_verify(self, &a_pub, &a_proof) {
                 Ok(res) => {
-                    self.unsuccessful_tries = 0;
+                    // the controller knows the setup code
+                    database
+                        .lock()
+                        .expect("couldn't access database")
+                        .set_pair_setup_attempts(&PairSetupAttempts::default())
+                        .map_err(|_| tlv::ErrorContainer::new(StepNumber::VerifyRes as u8, tlv::Error::Unknown))?;
                     Ok(res)
                 },
                 Err(err) => {
-                    self.unsuccessful_tries += 1;
+                    // a proof can only be checked once per SRP session
+                    self.release_lock();
+                    if let tlv::Error::Authentication = err {
+                        record_failed_attempt(database)
+                            .map_err(|_| tlv::ErrorContainer::new(StepNumber::VerifyRes as u8, tlv::Error::Unknown))?;
+                    }
                     Err(tlv::ErrorContainer::new(StepNumber::VerifyRes as u8, err))
                 },
             },
-            Step::Exchange { data } => match handle_exchange(self, config, database, event_emitter, &data) {
-                Ok(res) => {
-                    self.unsuccessful_tries = 0;
-                    Ok(res)
-                },
-                Err(err) => {
-                    self.unsuccessful_tries += 1;
-                    Err(tlv::ErrorContainer::new(StepNumber::ExchangeRes as u8, err))
-                },
+            Step::Exchange { data } => {
+                let res = handle_exchange(self, config, database, event_emitter, &data);
+                self.release_lock();
+                match res {
+                    Ok(res) => Ok(res),
+                    Err(err) => Err(tlv::ErrorContainer::new(StepNumber::ExchangeRes as u8, err)),
+                }
             },
         }
     }
 }
 
-fn handle_start(handler: &mut PairSetup, database: &DatabasePtr) -> Result<tlv::Container, tlv::Error> {
-    debug!("M1: Got SRP Start Request");
+/// Number of unsuccessful Pair Setup attempts after which Pair Setup is refused until the pairings
+/// of the accessory are reset.
+const MAX_PAIR_SETUP_ATTEMPTS: u64 = 100;
+/// Upper bound of the time a client has to wait after an unsuccessful Pair Setup attempt.
+const MAX_RETRY_DELAY: u64 = 60 * 60;
+
+/// Returns the time a client has to wait after the given number of unsuccessful Pair Setup attempts.
+/// The time doubles with every attempt.
+fn retry_delay(attempts: u64) -> Duration {
+    let secs = match attempts {
+        0 => 0,
+        a if a > 12 => MAX_RETRY_DELAY,
+        a => min(1 << (a - 1), MAX_RETRY_DELAY),
+    };
+    Duration::from_secs(secs)
+}
 
-    if handler.unsuccessful_tries > 99 {
-        return Err(tlv::Error::MaxTries);
+/// Refuses Pair Setup once the maximum number of unsuccessful attempts is reached and makes the
+/// client back off after every unsuccessful attempt.
+fn check_attempts(database: &DatabasePtr) -> Result<(), tlv::ErrorContainer> {
+    let attempts = database
+        .lock()
+        .expect("couldn't access database")
+        .get_pair_setup_attempts()
+        .map_err(|_| tlv::ErrorContainer::new(StepNumber::StartRes as u8, tlv::Error::Unknown))?;
+
+    if attempts.count >= MAX_PAIR_SETUP_ATTEMPTS {
+        return Err(tlv::ErrorContainer::new(
+            StepNumber::StartRes as u8,
+            tlv::Error::MaxTries,
+        ));
     }
 
-    let accessory = Device::load_from(database)?;
+    if let Some(last_attempt) = attempts.last_attempt {
+        let retry_at = last_attempt + retry_delay(attempts.count);
+        if let Ok(remaining) = retry_at.duration_since(SystemTime::now()) {
+            // the delay is rounded up to full seconds
+            let secs = remaining.as_secs() + if remaining.subsec_nanos() > 0 { 1 } else { 0 };
+            return Err(tlv::ErrorContainer::backoff(StepNumber::StartRes as u8, secs as usize));
+        }
+    }
+
+    Ok(())
+}
+
+fn record_failed_attempt(database: &DatabasePtr) -> crate::Result<()> {
+    let d = database.lock().expect("couldn't access database");
+    let mut attempts = d.get_pair_setup_attempts()?;
+    attempts.count += 1;
+    attempts.last_attempt = Some(SystemTime::now());
+    d.set_pair_setup_attempts(&attempts)
+}
+
+fn handle_start(handler: &mut PairSetup, database: &DatabasePtr, flags: u32) -> Result<tlv::Container, tlv::Error> {
+    debug!("M1: Got SRP Start Request");
+
+    // only the flags we support are taken into account and echoed back
+    let flags = flags & (Flag::Transient as u32 | Flag::Split as u32);
 
     let rng = rand::thread_rng();
-    let salt = rng.sample_iter::<u8, Standard>(Standard).take(16).collect::<Vec<u8>>(); // s
     let b = rng.sample_iter::<u8, Standard>(Standard).take(64).collect::<Vec<u8>>();
 
-    let private_key = srp_private_key::<Sha512>(b"Pair-Setup", accessory.pin.as_bytes(), &salt); // x = H(s | H(I | ":" | P))
-    let srp_client = SrpClient::<Sha512>::new(&private_key, &G_3072);
-    let verifier = srp_client.get_password_verifier(&private_key); // v = g^x
+    let (salt, verifier) = if Flag::Split.is_set(flags) && !Flag::Transient.is_set(flags) {
+        let split_verifier = handler
+            .split_verifier
+            .lock()
+            .expect("couldn't access split verifier")
+            .clone()
+            .ok_or(tlv::Error::Unavailable)?;
+        (split_verifier.salt, split_verifier.verifier)
+    } else {
+        let accessory = Device::load_from(database)?;
+
+        let salt = rng.sample_iter::<u8, Standard>(Standard).take(16).collect::<Vec<u8>>(); // s
+        let private_key = srp_private_key::<Sha512>(b"Pair-Setup", accessory.pin.as_bytes(), &salt); // x = H(s | H(I | ":" | P))
+        let srp_client = SrpClient::<Sha512>::new(&private_key, &G_3072);
+        let verifier = srp_client.get_password_verifier(&private_key); // v = g^x
+        (salt, verifier)
+    };
 
     let user = UserRecord {
         username: b"Pair-Setup",
@@ -177,16 +347,22 @@ fn handle_start(handler: &mut PairSetup, database: &DatabasePtr) -> Result<tlv::
         verifier: verifier.clone(),
         b: b.clone(),
         b_pub: b_pub.clone(),
+        flags,
         shared_secret: None,
     });
 
     debug!("M2: Sending SRP Start Response");
 
-    Ok(vec![
+    let mut res = vec![
         Value::State(StepNumber::StartRes as u8),
         Value::PublicKey(b_pub),
         Value::Salt(salt.clone()),
-    ])
+    ];
+    if flags != 0 {
+        res.push(Value::Flags(flags));
+    }
+
+    Ok(res)
 }
 
 fn handle_verify(handler: &mut PairSetup, a_pub: &[u8], a_proof: &[u8]) -> Result<tlv::Container, tlv::Error> {
@@ -210,6 +386,10 @@ fn handle_verify(handler: &mut PairSetup, a_pub: &[u8], a_proof: &[u8]) -> Resul
             &G_3072,
         )?;
 
+        if Flag::Transient.is_set(session.flags) {
+            start_transient_session(handler, &shared_secret)?;
+        }
+
         debug!("M4: Sending SRP Verify Response");
 
         Ok(vec![Value::State(StepNumber::VerifyRes as u8), Value::Proof(b_proof)])
@@ -218,6 +398,39 @@ fn handle_verify(handler: &mut PairSetup, a_pub: &[u8], a_proof: &[u8]) -> Resul
     }
 }
 
+/// Ends a transient Pair Setup after M4 by establishing a session that isn't bound to a pairing.
+/// With the `Split` flag set, the SRP verifier is kept for a following Pair Setup.
+fn start_transient_session(handler: &mut PairSetup, shared_secret: &[u8]) -> Result<(), tlv::Error> {
+    let session = handler.session.take().ok_or(tlv::Error::Unknown)?;
+    handler.release_lock();
+
+    let mut read_key = [0; 32];
+    let mut write_key = [0; 32];
+    let salt = hmac::SigningKey::new(&digest::SHA512, b"SplitSetupSalt");
+    hkdf::extract_and_expand(&salt, shared_secret, b"ControllerEncrypt-Control", &mut read_key);
+    hkdf::extract_and_expand(&salt, shared_secret, b"AccessoryEncrypt-Control", &mut write_key);
+
+    let sender = handler
+        .session_sender
+        .lock()
+        .expect("couldn't access session sender")
+        .take()
+        .ok_or(tlv::Error::Unknown)?;
+    let _session = sender.send(tcp::Session {
+        controller_id: None,
+        codec: tcp::SessionCodec::from_keys(read_key, write_key),
+    });
+
+    if Flag::Split.is_set(session.flags) {
+        *handler.split_verifier.lock().expect("couldn't access split verifier") = Some(SplitVerifier {
+            salt: session.salt,
+            verifier: session.verifier,
+        });
+    }
+
+    Ok(())
+}
+
 fn handle_exchange(
     handler: &mut PairSetup,
     config: &ConfigPtr,
@@ -229,6 +442,9 @@ fn handle_exchange(
 
     if let Some(ref mut session) = handler.session {
         if let Some(ref mut shared_secret) = session.shared_secret {
+            if data.len() < 16 {
+                return Err(tlv::Error::Unknown);
+            }
             let encrypted_data = Vec::from(&data[..data.len() - 16]);
             let auth_tag = Vec::from(&data[data.len() - 16..]);
 
@@ -248,10 +464,13 @@ fn handle_exchange(
                 &mut decrypted_data,
             )?;
 
-            let sub_tlv = tlv::decode(decrypted_data);
-            let device_pairing_id = sub_tlv.get(&(Type::Identifier as u8)).ok_or(tlv::Error::Unknown)?;
-            let device_ltpk = sub_tlv.get(&(Type::PublicKey as u8)).ok_or(tlv::Error::Unknown)?;
-            let device_signature = sub_tlv.get(&(Type::Signature as u8)).ok_or(tlv::Error::Unknown)?;
+            let sub_tlv = tlv::decode(decrypted_data)?;
+            let device_pairing_id = sub_tlv.get(Type::Identifier as u8).ok_or(tlv::Error::Unknown)?;
+            let device_ltpk = sub_tlv.get(Type::PublicKey as u8).ok_or(tlv::Error::Unknown)?;
+            let device_signature = sub_tlv.get(Type::Signature as u8).ok_or(tlv::Error::Unknown)?;
+            if device_ltpk.len() != 32 || device_signature.len() != 64 {
+                return Err(tlv::Error::Unknown);
+            }
 
             let mut device_x = [0; 32];
             let salt = hmac::SigningKey::new(&digest::SHA512, b"Pair-Setup-Controller-Sign-Salt");
@@ -295,11 +514,12 @@ fn handle_exchange(
             accessory_info.extend(&accessory.public_key);
             let accessory_signature = ed25519::signature(&accessory_info, &accessory.private_key);
 
-            let mut sub_tlv: HashMap<u8, Vec<u8>> = HashMap::new();
-            Value::Identifier(accessory.id).into_map(&mut sub_tlv);
-            Value::PublicKey(accessory.public_key.to_vec()).into_map(&mut sub_tlv);
-            Value::Signature(accessory_signature.to_vec()).into_map(&mut sub_tlv);
-            let encoded_sub_tlv = tlv::encode(sub_tlv);
+            let encoded_sub_tlv = vec![
+                Value::Identifier(accessory.id),
+                Value::PublicKey(accessory.public_key.to_vec()),
+                Value::Signature(accessory_signature.to_vec()),
+            ]
+            .encode();
 
             let mut encrypted_data = Vec::new();
             let mut nonce = vec![0; 4];
@@ -369,3 +589,26 @@ fn verify_client_proof<D: Digest>(
         Err(tlv::Error::Authentication)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::http::handler::assert_rejects_truncated_body;
+
+    #[test]
+    fn parse_truncated_body() {
+        let handler = PairSetup::new(
+            Arc::new(Mutex::new(None)),
+            Arc::new(Mutex::new(None)),
+            Arc::new(Mutex::new(None)),
+            ConnectionId(0),
+        );
+        let body = tlv::encode(vec![
+            (Type::State as u8, vec![StepNumber::VerifyReq as u8]),
+            (Type::PublicKey as u8, vec![1; 384]),
+            (Type::Proof as u8, vec![2; 64]),
+        ]);
+
+        assert_rejects_truncated_body(&handler, &body, 300);
+    }
+}
@@ -1,8 +1,12 @@
-use std::{collections::HashMap, str};
+use std::{
+    collections::HashMap,
+    str,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use chacha20_poly1305_aead;
 use crypto::{curve25519, ed25519};
-use futures::sync::oneshot;
 use log::debug;
 use rand::{self, Rng};
 use ring::{digest, hkdf, hmac};
@@ -13,7 +17,7 @@ use crate::{
     db::DatabasePtr,
     event::EventEmitterPtr,
     protocol::{
-        tlv::{self, Type, Value},
+        tlv::{self, Encodable, Method, Type, Value},
         Device,
         IdPtr,
         Pairing,
@@ -28,16 +32,78 @@ struct Session {
     session_key: [u8; 32],
 }
 
+#[derive(Clone, Copy)]
+struct CachedSession {
+    controller_id: Uuid,
+    shared_secret: [u8; 32],
+    expires_at: Instant,
+}
+
+/// Cache of the shared secrets of past sessions, keyed by their session ID. Controllers can use a
+/// session ID to resume a session via Pair Resume. Every session ID can only be used once.
+pub struct SessionCache {
+    sessions: HashMap<[u8; 8], CachedSession>,
+}
+
+pub type SessionCachePtr = Arc<Mutex<SessionCache>>;
+
+impl SessionCache {
+    /// Creates a new, empty `SessionCache`.
+    pub fn new() -> SessionCache {
+        SessionCache {
+            sessions: HashMap::new(),
+        }
+    }
+
+    /// Caches the shared secret of a session for the given time and drops expired sessions.
+    fn insert(&mut self, session_id: [u8; 8], controller_id: Uuid, shared_secret: [u8; 32], expiry: Duration) {
+        let now = Instant::now();
+        self.sessions.retain(|_, s| s.expires_at > now);
+        self.sessions.insert(session_id, CachedSession {
+            controller_id,
+            shared_secret,
+            expires_at: now + expiry,
+        });
+    }
+
+    /// Returns the session with the given ID if it hasn't expired, leaving it in the cache.
+    fn get(&self, session_id: &[u8]) -> Option<CachedSession> {
+        self.sessions
+            .get(&cache_key(session_id)?)
+            .filter(|s| s.expires_at > Instant::now())
+            .cloned()
+    }
+
+    /// Removes the session with the given ID from the cache and returns whether it was still there.
+    fn remove(&mut self, session_id: &[u8]) -> bool {
+        match cache_key(session_id) {
+            Some(id) => self.sessions.remove(&id).is_some(),
+            None => false,
+        }
+    }
+}
+
+fn cache_key(session_id: &[u8]) -> Option<[u8; 8]> {
+    if session_id.len() != 8 {
+        return None;
+    }
+    let mut id = [0; 8];
+    id.copy_from_slice(session_id);
+    Some(id)
+}
+
 pub struct PairVerify {
     session: Option<Session>,
-    session_sender: Option<oneshot::Sender<tcp::Session>>,
+    session_sender: tcp::SessionSenderPtr,
+    session_cache: SessionCachePtr,
 }
 
 impl PairVerify {
-    pub fn new(session_sender: oneshot::Sender<tcp::Session>) -> PairVerify {
+    pub fn new(session_sender: tcp::SessionSenderPtr, session_cache: SessionCachePtr) -> PairVerify {
         PairVerify {
             session: None,
-            session_sender: Some(session_sender),
+            session_sender,
+            session_cache,
         }
     }
 }
@@ -51,8 +117,17 @@ enum StepNumber {
 }
 
 pub enum Step {
-    Start { a_pub: Vec<u8> },
-    Finish { data: Vec<u8> },
+    Start {
+        a_pub: Vec<u8>,
+    },
+    Resume {
+        a_pub: Vec<u8>,
+        session_id: Vec<u8>,
+        auth_tag: Vec<u8>,
+    },
+    Finish {
+        data: Vec<u8>,
+    },
 }
 
 impl TlvHandler for PairVerify {
@@ -60,23 +135,40 @@ impl TlvHandler for PairVerify {
     type Result = tlv::Container;
 
     fn parse(&self, body: Vec<u8>) -> Result<Step, tlv::ErrorContainer> {
-        let decoded = tlv::decode(body);
-        match decoded.get(&(Type::State as u8)) {
-            Some(method) => match method[0] {
+        let decoded = tlv::decode(body).map_err(|e| {
+            debug!("Couldn't decode TLVs: {}", e);
+            tlv::ErrorContainer::new(StepNumber::Unknown as u8, tlv::Error::Unknown)
+        })?;
+        match decoded.get(Type::State as u8).and_then(|s| s.first()) {
+            Some(&method) => match method {
                 x if x == StepNumber::StartReq as u8 => {
-                    let a_pub = decoded.get(&(Type::PublicKey as u8)).ok_or(tlv::ErrorContainer::new(
+                    let a_pub = decoded.get(Type::PublicKey as u8).ok_or(tlv::ErrorContainer::new(
                         StepNumber::StartRes as u8,
                         tlv::Error::Unknown,
                     ))?;
+                    let method = decoded.get(Type::Method as u8).and_then(|m| m.first().cloned());
+                    if method == Some(Method::PairResume as u8) {
+                        let session_id = decoded.get(Type::SessionId as u8).ok_or(tlv::ErrorContainer::new(
+                            StepNumber::StartRes as u8,
+                            tlv::Error::Unknown,
+                        ))?;
+                        let auth_tag = decoded.get(Type::EncryptedData as u8).ok_or(tlv::ErrorContainer::new(
+                            StepNumber::StartRes as u8,
+                            tlv::Error::Unknown,
+                        ))?;
+                        return Ok(Step::Resume {
+                            a_pub: a_pub.clone(),
+                            session_id: session_id.clone(),
+                            auth_tag: auth_tag.clone(),
+                        });
+                    }
                     Ok(Step::Start { a_pub: a_pub.clone() })
                 },
                 x if x == StepNumber::FinishReq as u8 => {
-                    let data = decoded
-                        .get(&(Type::EncryptedData as u8))
-                        .ok_or(tlv::ErrorContainer::new(
-                            StepNumber::FinishRes as u8,
-                            tlv::Error::Unknown,
-                        ))?;
+                    let data = decoded.get(Type::EncryptedData as u8).ok_or(tlv::ErrorContainer::new(
+                        StepNumber::FinishRes as u8,
+                        tlv::Error::Unknown,
+                    ))?;
                     Ok(Step::Finish { data: data.clone() })
                 },
                 _ => Err(tlv::ErrorContainer::new(StepNumber::Unknown as u8, tlv::Error::Unknown)),
@@ -89,16 +181,25 @@ impl TlvHandler for PairVerify {
         &mut self,
         step: Step,
         _: &IdPtr,
-        _: &ConfigPtr,
+        config: &ConfigPtr,
         database: &DatabasePtr,
         _: &EventEmitterPtr,
     ) -> Result<tlv::Container, tlv::ErrorContainer> {
+        let session_resume_expiry = config.lock().expect("couldn't access config").session_resume_expiry;
         match step {
             Step::Start { a_pub } => match handle_start(self, database, a_pub) {
                 Ok(res) => Ok(res),
                 Err(err) => Err(tlv::ErrorContainer::new(StepNumber::StartRes as u8, err)),
             },
-            Step::Finish { data } => match handle_finish(self, database, &data) {
+            Step::Resume {
+                a_pub,
+                session_id,
+                auth_tag,
+            } => match handle_resume(self, database, a_pub, &session_id, &auth_tag, session_resume_expiry) {
+                Ok(res) => Ok(res),
+                Err(err) => Err(tlv::ErrorContainer::new(StepNumber::StartRes as u8, err)),
+            },
+            Step::Finish { data } => match handle_finish(self, database, &data, session_resume_expiry) {
                 Ok(res) => Ok(res),
                 Err(err) => Err(tlv::ErrorContainer::new(StepNumber::FinishRes as u8, err)),
             },
@@ -113,6 +214,10 @@ fn handle_start(
 ) -> Result<tlv::Container, tlv::Error> {
     debug!("M1: Got Verify Start Request");
 
+    if a_pub.len() != 32 {
+        return Err(tlv::Error::Unknown);
+    }
+
     let mut rng = rand::thread_rng();
     let b = rng.gen::<[u8; 32]>();
     let b_pub = curve25519::curve25519_base(&b);
@@ -125,16 +230,19 @@ fn handle_start(
     accessory_info.extend(&a_pub);
     let accessory_signature = ed25519::signature(&accessory_info, &accessory.private_key);
 
-    let mut sub_tlv: HashMap<u8, Vec<u8>> = HashMap::new();
-    let (t, v) = Value::Identifier(accessory.id).as_tlv();
-    sub_tlv.insert(t, v);
-    let (t, v) = Value::Signature(accessory_signature.to_vec()).as_tlv();
-    sub_tlv.insert(t, v);
-    let encoded_sub_tlv = tlv::encode(sub_tlv);
+    let encoded_sub_tlv = vec![
+        Value::Identifier(accessory.id),
+        Value::Signature(accessory_signature.to_vec()),
+    ]
+    .encode();
 
     let mut session_key = [0; 32];
-    let salt = hmac::SigningKey::new(&digest::SHA512, b"Pair-Verify-Encrypt-Salt");
-    hkdf::extract_and_expand(&salt, &shared_secret, b"Pair-Verify-Encrypt-Info", &mut session_key);
+    derive_key(
+        b"Pair-Verify-Encrypt-Salt",
+        &shared_secret,
+        b"Pair-Verify-Encrypt-Info",
+        &mut session_key,
+    );
 
     handler.session = Some(Session {
         b_pub,
@@ -158,10 +266,128 @@ fn handle_start(
     ])
 }
 
-fn handle_finish(handler: &mut PairVerify, database: &DatabasePtr, data: &[u8]) -> Result<tlv::Container, tlv::Error> {
+fn handle_resume(
+    handler: &mut PairVerify,
+    database: &DatabasePtr,
+    a_pub: Vec<u8>,
+    session_id: &[u8],
+    auth_tag: &[u8],
+    session_resume_expiry: Duration,
+) -> Result<tlv::Container, tlv::Error> {
+    debug!("M1: Got Resume Request");
+
+    let cached_session = handler
+        .session_cache
+        .lock()
+        .expect("couldn't access session cache")
+        .get(session_id);
+    let cached_session = match cached_session {
+        Some(cached_session) => cached_session,
+        None => {
+            debug!("Unknown or expired session ID, falling back to Pair Verify");
+            return handle_start(handler, database, a_pub);
+        },
+    };
+
+    let mut salt = a_pub.clone();
+    salt.extend(session_id);
+    let mut request_key = [0; 32];
+    derive_key(
+        &salt,
+        &cached_session.shared_secret,
+        b"Pair-Resume-Request-Info",
+        &mut request_key,
+    );
+    let mut nonce = vec![0; 4];
+    nonce.extend(b"PR-Msg01");
+    let verified = chacha20_poly1305_aead::decrypt(&request_key, &nonce, &[], &[], auth_tag, &mut Vec::new()).is_ok();
+    // the pairing of the controller could have been removed since the session was cached
+    if !verified || Pairing::load_from(cached_session.controller_id, database).is_err() {
+        debug!("Couldn't verify Resume Request, falling back to Pair Verify");
+        return handle_start(handler, database, a_pub);
+    }
+    // the session is only consumed once the request is verified, so a forged request can't evict it
+    if !handler
+        .session_cache
+        .lock()
+        .expect("couldn't access session cache")
+        .remove(session_id)
+    {
+        debug!("Session ID was used concurrently, falling back to Pair Verify");
+        return handle_start(handler, database, a_pub);
+    }
+
+    let new_session_id = rand::thread_rng().gen::<[u8; 8]>();
+    let mut salt = a_pub;
+    salt.extend(&new_session_id);
+
+    let mut response_key = [0; 32];
+    derive_key(
+        &salt,
+        &cached_session.shared_secret,
+        b"Pair-Resume-Response-Info",
+        &mut response_key,
+    );
+    let mut nonce = vec![0; 4];
+    nonce.extend(b"PR-Msg02");
+    let auth_tag = chacha20_poly1305_aead::encrypt(&response_key, &nonce, &[], &[], &mut Vec::new())?;
+
+    let mut shared_secret = [0; 32];
+    derive_key(
+        &salt,
+        &cached_session.shared_secret,
+        b"Pair-Resume-Shared-Secret-Info",
+        &mut shared_secret,
+    );
+
+    if let Some(sender) = handler
+        .session_sender
+        .lock()
+        .expect("couldn't access session sender")
+        .take()
+    {
+        let encrypted_session = tcp::Session {
+            controller_id: Some(cached_session.controller_id),
+            codec: tcp::SessionCodec::new(&shared_secret),
+        };
+        let _session = sender.send(encrypted_session);
+    } else {
+        return Err(tlv::Error::Unknown);
+    }
+
+    handler
+        .session_cache
+        .lock()
+        .expect("couldn't access session cache")
+        .insert(
+            new_session_id,
+            cached_session.controller_id,
+            shared_secret,
+            session_resume_expiry,
+        );
+
+    debug!("M2: Sending Resume Response");
+
+    Ok(vec![
+        Value::State(StepNumber::StartRes as u8),
+        Value::Method(Method::PairResume),
+        Value::SessionId(new_session_id.to_vec()),
+        Value::EncryptedData(auth_tag.to_vec()),
+    ])
+}
+
+fn handle_finish(
+    handler: &mut PairVerify,
+    database: &DatabasePtr,
+    data: &[u8],
+    session_resume_expiry: Duration,
+) -> Result<tlv::Container, tlv::Error> {
     debug!("M3: Got Verify Finish Request");
 
     if let Some(ref mut session) = handler.session {
+        if data.len() < 16 {
+            return Err(tlv::Error::Unknown);
+        }
         let encrypted_data = Vec::from(&data[..data.len() - 16]);
         let auth_tag = Vec::from(&data[data.len() - 16..]);
 
@@ -177,9 +403,12 @@ fn handle_finish(handler: &mut PairVerify, database: &DatabasePtr, data: &[u8])
             &mut decrypted_data,
         )?;
 
-        let sub_tlv = tlv::decode(decrypted_data);
-        let device_pairing_id = sub_tlv.get(&(Type::Identifier as u8)).ok_or(tlv::Error::Unknown)?;
-        let device_signature = sub_tlv.get(&(Type::Signature as u8)).ok_or(tlv::Error::Unknown)?;
+        let sub_tlv = tlv::decode(decrypted_data)?;
+        let device_pairing_id = sub_tlv.get(Type::Identifier as u8).ok_or(tlv::Error::Unknown)?;
+        let device_signature = sub_tlv.get(Type::Signature as u8).ok_or(tlv::Error::Unknown)?;
+        if device_signature.len() != 64 {
+            return Err(tlv::Error::Unknown);
+        }
 
         let uuid_str = str::from_utf8(device_pairing_id)?;
         let pairing_uuid = Uuid::parse_str(uuid_str)?;
@@ -193,16 +422,34 @@ fn handle_finish(handler: &mut PairVerify, database: &DatabasePtr, data: &[u8])
             return Err(tlv::Error::Authentication);
         }
 
-        if let Some(sender) = handler.session_sender.take() {
+        if let Some(sender) = handler
+            .session_sender
+            .lock()
+            .expect("couldn't access session sender")
+            .take()
+        {
             let encrypted_session = tcp::Session {
-                controller_id: pairing_uuid,
-                shared_secret: session.shared_secret,
+                controller_id: Some(pairing_uuid),
+                codec: tcp::SessionCodec::new(&session.shared_secret),
             };
             let _session = sender.send(encrypted_session);
         } else {
             return Err(tlv::Error::Unknown);
         }
 
+        let mut session_id = [0; 8];
+        derive_key(
+            b"Pair-Verify-ResumeSessionID-Salt",
+            &session.shared_secret,
+            b"Pair-Verify-ResumeSessionID-Info",
+            &mut session_id,
+        );
+        handler
+            .session_cache
+            .lock()
+            .expect("couldn't access session cache")
+            .insert(session_id, pairing_uuid, session.shared_secret, session_resume_expiry);
+
         debug!("M4: Sending Verify Finish Response");
 
         Ok(vec![Value::State(StepNumber::FinishRes as u8)])
@@ -210,3 +457,66 @@ fn handle_finish(handler: &mut PairVerify, database: &DatabasePtr, data: &[u8])
         Err(tlv::Error::Unknown)
     }
 }
+
+fn derive_key(salt: &[u8], secret: &[u8], info: &[u8], key: &mut [u8]) {
+    let salt = hmac::SigningKey::new(&digest::SHA512, salt);
+    hkdf::extract_and_expand(&salt, secret, info, key);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::{Database, InMemoryStorage},
+        transport::http::handler::assert_rejects_truncated_body,
+    };
+
+    #[test]
+    fn parse_truncated_body() {
+        let handler = PairVerify::new(Arc::new(Mutex::new(None)), Arc::new(Mutex::new(SessionCache::new())));
+        let body = tlv::encode(vec![
+            (Type::State as u8, vec![StepNumber::StartReq as u8]),
+            (Type::PublicKey as u8, vec![1; 32]),
+        ]);
+
+        assert_rejects_truncated_body(&handler, &body, 20);
+    }
+
+    #[test]
+    fn session_cache_get_and_remove() {
+        let mut cache = SessionCache::new();
+        cache.insert([1; 8], Uuid::new_v4(), [2; 32], Duration::from_secs(60));
+        cache.insert([3; 8], Uuid::new_v4(), [4; 32], Duration::from_secs(0));
+
+        assert_eq!(cache.get(&[1; 8]).map(|s| s.shared_secret), Some([2; 32]));
+        assert!(cache.get(&[1; 8]).is_some());
+        assert!(cache.get(&[3; 8]).is_none());
+        assert!(cache.get(&[1; 7]).is_none());
+
+        assert!(cache.remove(&[1; 8]));
+        assert!(!cache.remove(&[1; 8]));
+        assert!(cache.get(&[1; 8]).is_none());
+    }
+
+    #[test]
+    fn forged_resume_request_keeps_session() {
+        let session_cache = Arc::new(Mutex::new(SessionCache::new()));
+        session_cache
+            .lock()
+            .unwrap()
+            .insert([1; 8], Uuid::new_v4(), [2; 32], Duration::from_secs(60));
+        let mut handler = PairVerify::new(Arc::new(Mutex::new(None)), session_cache.clone());
+        let database = Arc::new(Mutex::new(Database::new(Box::new(InMemoryStorage::new()))));
+
+        // the fallback to Pair Verify fails as there's no device in the database
+        let _ = handle_resume(
+            &mut handler,
+            &database,
+            vec![5; 32],
+            &[1; 8],
+            &[6; 16],
+            Duration::from_secs(60),
+        );
+        assert!(session_cache.lock().unwrap().get(&[1; 8]).is_some());
+    }
+}
@@ -50,23 +50,27 @@ impl TlvHandler for Pairings {
     type Result = tlv::Container;
 
     fn parse(&self, body: Vec<u8>) -> Result<HandlerType, tlv::ErrorContainer> {
-        let decoded = tlv::decode(body);
-        if decoded.get(&(Type::State as u8)) != Some(&vec![1]) {
+        let decoded = tlv::decode(body).map_err(|e| {
+            debug!("Couldn't decode TLVs: {}", e);
+            tlv::ErrorContainer::new(StepNumber::Unknown as u8, tlv::Error::Unknown)
+        })?;
+        if decoded.get(Type::State as u8) != Some(&vec![1]) {
             return Err(tlv::ErrorContainer::new(0, tlv::Error::Unknown));
         }
-        match decoded.get(&(Type::Method as u8)) {
-            Some(handler) => match handler[0] {
+        match decoded.get(Type::Method as u8).and_then(|m| m.first()) {
+            Some(&handler) => match handler {
                 x if x == HandlerNumber::Add as u8 => {
                     let pairing_id = decoded
-                        .get(&(Type::Identifier as u8))
+                        .get(Type::Identifier as u8)
                         .ok_or(tlv::ErrorContainer::new(StepNumber::Res as u8, tlv::Error::Unknown))?;
                     let ltpk = decoded
-                        .get(&(Type::PublicKey as u8))
+                        .get(Type::PublicKey as u8)
                         .ok_or(tlv::ErrorContainer::new(StepNumber::Res as u8, tlv::Error::Unknown))?;
                     let perms = decoded
-                        .get(&(Type::Permissions as u8))
+                        .get(Type::Permissions as u8)
+                        .and_then(|p| p.first())
                         .ok_or(tlv::ErrorContainer::new(StepNumber::Res as u8, tlv::Error::Unknown))?;
-                    let permissions = Permissions::from_u8(perms[0])
+                    let permissions = Permissions::from_u8(*perms)
                         .map_err(|_| tlv::ErrorContainer::new(StepNumber::Res as u8, tlv::Error::Unknown))?;
                     Ok(HandlerType::Add {
                         pairing_id: pairing_id.clone(),
@@ -76,7 +80,7 @@ impl TlvHandler for Pairings {
                 },
                 x if x == HandlerNumber::Remove as u8 => {
                     let pairing_id = decoded
-                        .get(&(Type::Identifier as u8))
+                        .get(Type::Identifier as u8)
                         .ok_or(tlv::ErrorContainer::new(StepNumber::Res as u8, tlv::Error::Unknown))?;
                     Ok(HandlerType::Remove {
                         pairing_id: pairing_id.clone(),
@@ -140,6 +144,10 @@ fn handle_add(
 
     check_admin(database, controller_id)?;
 
+    if ltpk.len() != 32 {
+        return Err(tlv::Error::Unknown);
+    }
+
     let uuid_str = str::from_utf8(&pairing_id)?;
     let pairing_uuid = Uuid::parse_str(uuid_str)?;
 
@@ -224,7 +232,7 @@ fn handle_list(database: &DatabasePtr, controller_id: &IdPtr) -> Result<tlv::Con
         list.push(Value::Identifier(pairing.id.to_hyphenated().to_string()));
         list.push(Value::PublicKey(pairing.public_key.to_vec()));
         list.push(Value::Permissions(pairing.permissions.clone()));
-        if i < pairings.len() {
+        if i + 1 < pairings.len() {
             list.push(Value::Separator);
         }
     }
@@ -249,3 +257,26 @@ fn check_admin(database: &DatabasePtr, controller_id: &IdPtr) -> Result<(), tlv:
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::http::handler::assert_rejects_truncated_body;
+
+    #[test]
+    fn parse_truncated_body() {
+        let handler = Pairings::new();
+        let body = tlv::encode(vec![
+            (Type::State as u8, vec![1]),
+            (Type::Method as u8, vec![HandlerNumber::Add as u8]),
+            (
+                Type::Identifier as u8,
+                Uuid::new_v4().to_hyphenated().to_string().into_bytes(),
+            ),
+            (Type::PublicKey as u8, vec![1; 32]),
+            (Type::Permissions as u8, vec![1]),
+        ]);
+
+        assert_rejects_truncated_body(&handler, &body, body.len() - 1);
+    }
+}
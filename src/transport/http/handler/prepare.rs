@@ -0,0 +1,73 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use hyper::{Body, Response, StatusCode, Uri};
+use serde_json::{self, json};
+
+use crate::{
+    config::ConfigPtr,
+    db::{AccessoryList, DatabasePtr},
+    event::EventEmitterPtr,
+    protocol::IdPtr,
+    transport::http::{handler::JsonHandler, json_response, server::EventSubscriptions, PrepareObject, Status},
+    Result,
+};
+
+/// A Timed Write announced by a Controller through `/prepare`.
+pub struct PreparedWrite {
+    pid: u64,
+    expires: Instant,
+}
+
+impl PreparedWrite {
+    /// Returns whether a write request carrying the given PID is covered by the `PreparedWrite`.
+    pub fn is_valid_for(&self, pid: u64) -> bool { self.pid == pid && Instant::now() <= self.expires }
+}
+
+/// Pointer to the `PreparedWrite` of a connection.
+pub type PreparedWritePtr = Arc<Mutex<Option<PreparedWrite>>>;
+
+pub struct Prepare {
+    prepared_write: PreparedWritePtr,
+}
+
+impl Prepare {
+    pub fn new(prepared_write: PreparedWritePtr) -> Prepare { Prepare { prepared_write } }
+}
+
+impl JsonHandler for Prepare {
+    fn handle(
+        &mut self,
+        _: Uri,
+        body: Vec<u8>,
+        _: &IdPtr,
+        _: &EventSubscriptions,
+        _: &ConfigPtr,
+        _: &DatabasePtr,
+        _: &AccessoryList,
+        _: &EventEmitterPtr,
+    ) -> Result<Response<Body>> {
+        let status = match serde_json::from_slice::<PrepareObject>(&body) {
+            // a TTL too large to be represented as an `Instant` is as invalid as a malformed body
+            Ok(prepare) => match Instant::now().checked_add(Duration::from_millis(prepare.ttl)) {
+                Some(expires) => {
+                    *self.prepared_write.lock().expect("couldn't access prepared_write") = Some(PreparedWrite {
+                        pid: prepare.pid,
+                        expires,
+                    });
+                    Status::Success
+                },
+                None => Status::InvalidValueInRequest,
+            },
+            Err(_) => Status::InvalidValueInRequest,
+        };
+
+        let res = serde_json::to_vec(&json!({ "status": status as i32 }))?;
+        match status {
+            Status::Success => json_response(res, StatusCode::OK),
+            _ => json_response(res, StatusCode::BAD_REQUEST),
+        }
+    }
+}
@@ -13,10 +13,13 @@ use crate::{
     Result,
 };
 
+pub(crate) mod event_queue;
 pub(crate) mod handler;
 pub(crate) mod server;
 
+/// HAP status code of a characteristic read or write.
 #[allow(dead_code)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Status {
     Success = 0,
     InsufficientPrivileges = -70401,
@@ -89,6 +92,12 @@ pub struct WriteObject {
     pub remote: Option<bool>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct WriteRequestBody {
+    characteristics: Vec<WriteObject>,
+    pid: Option<u64>,
+}
+
 #[derive(Debug, Serialize)]
 pub struct WriteResponseObject {
     pub iid: u64,
@@ -96,10 +105,18 @@ pub struct WriteResponseObject {
     pub status: i32,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct PrepareObject {
+    pub ttl: u64,
+    pub pid: u64,
+}
+
 #[derive(Debug, Serialize)]
 pub struct EventObject {
     pub iid: u64,
     pub aid: u64,
+    #[serde(skip)]
+    pub hap_type: HapType,
     pub value: serde_json::Value,
 }
 
@@ -1,23 +1,40 @@
 use std::{
-    net::SocketAddr,
+    collections::HashMap,
+    net::{self, SocketAddr},
     sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
 };
 
-use futures::{future, stream::Stream, sync::oneshot, Future};
-use hyper::{self, server::conn::Http, service::Service, Body, Method, Request, Response, StatusCode};
+use futures::{
+    channel::{mpsc, oneshot},
+    future::{self, BoxFuture, Either},
+    FutureExt,
+    StreamExt,
+};
+use hyper::{server::conn::Http, service::Service, Body, Method, Request, Response, StatusCode};
 use log::error;
 use route_recognizer::Router;
-use tokio::net::TcpListener;
+use tokio::{net::TcpListener, time::sleep};
 
 use crate::{
     config::ConfigPtr,
     db::{AccessoryList, DatabasePtr},
-    event::{Event, EventEmitterPtr},
+    event::{ConnectionId, Event, EventEmitterPtr},
     protocol::IdPtr,
     transport::{
         http::{
-            event_response,
-            handler::{self, accessories, characteristics, identify, pair_setup, pair_verify, pairings},
+            event_queue::EventQueue,
+            handler::{
+                self,
+                accessories,
+                characteristics,
+                identify,
+                pair_setup::{self, PairSetupLockPtr, SplitVerifierPtr},
+                pair_verify::{self, SessionCache, SessionCachePtr},
+                pairings,
+                prepare,
+            },
             status_response,
             EventObject,
         },
@@ -27,9 +44,13 @@ use crate::{
     Result,
 };
 
+/// Time the sessions get to finish the requests in flight once the server is shut down.
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
 enum Route {
     Get(Box<Mutex<dyn handler::Handler + Send>>),
     Post(Box<Mutex<dyn handler::Handler + Send>>),
+    Put(Box<Mutex<dyn handler::Handler + Send>>),
     GetPut {
         _get: Box<Mutex<dyn handler::Handler + Send>>,
         _put: Box<Mutex<dyn handler::Handler + Send>>,
@@ -55,18 +76,26 @@ impl Api {
         accessories: AccessoryList,
         event_emitter: EventEmitterPtr,
         session_sender: oneshot::Sender<Session>,
+        session_cache: SessionCachePtr,
+        split_verifier: SplitVerifierPtr,
+        pair_setup_lock: PairSetupLockPtr,
+        connection_id: ConnectionId,
     ) -> Api {
+        let prepared_write = Arc::new(Mutex::new(None));
+        // both Pair Setup and Pair Verify can establish the session of the connection
+        let session_sender = Arc::new(Mutex::new(Some(session_sender)));
+
         let mut router = Router::new();
         router.add(
             "/pair-setup",
             Route::Post(Box::new(Mutex::new(handler::TlvHandlerType::from(
-                pair_setup::PairSetup::new(),
+                pair_setup::PairSetup::new(session_sender.clone(), split_verifier, pair_setup_lock, connection_id),
             )))),
         );
         router.add(
             "/pair-verify",
             Route::Post(Box::new(Mutex::new(handler::TlvHandlerType::from(
-                pair_verify::PairVerify::new(session_sender),
+                pair_verify::PairVerify::new(session_sender, session_cache),
             )))),
         );
         router.add(
@@ -76,13 +105,19 @@ impl Api {
             )))),
         );
         router.add("/characteristics", Route::GetPut {
-            _get: Box::new(Mutex::new(handler::JsonHandlerType::from(
+            _get: Box::new(Mutex::new(handler::AsyncJsonHandlerType::from(
                 characteristics::GetCharacteristics::new(),
             ))),
-            _put: Box::new(Mutex::new(handler::JsonHandlerType::from(
-                characteristics::UpdateCharacteristics::new(),
+            _put: Box::new(Mutex::new(handler::AsyncJsonHandlerType::from(
+                characteristics::UpdateCharacteristics::new(prepared_write.clone(), connection_id),
             ))),
         });
+        router.add(
+            "/prepare",
+            Route::Put(Box::new(Mutex::new(handler::JsonHandlerType::from(
+                prepare::Prepare::new(prepared_write),
+            )))),
+        );
         router.add(
             "/pairings",
             Route::Post(Box::new(Mutex::new(handler::TlvHandlerType::from(
@@ -108,13 +143,14 @@ impl Api {
     }
 }
 
-impl Service for Api {
+impl Service<Request<Body>> for Api {
     type Error = Error;
-    type Future = Box<dyn Future<Item = Response<Self::ResBody>, Error = Self::Error> + Send>;
-    type ReqBody = Body;
-    type ResBody = Body;
+    type Future = BoxFuture<'static, Result<Self::Response>>;
+    type Response = Response<Body>;
 
-    fn call(&mut self, req: Request<Self::ReqBody>) -> Self::Future {
+    fn poll_ready(&mut self, _: &mut Context) -> Poll<Result<()>> { Poll::Ready(Ok(())) }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
         let (parts, body) = req.into_parts();
         let router = self.router.clone();
         let controller_id = self.controller_id.clone();
@@ -124,84 +160,136 @@ impl Service for Api {
         let accessories = self.accessories.clone();
         let event_emitter = self.event_emitter.clone();
 
-        Box::new(
-            body.fold(vec![], |mut v, c| {
-                v.extend(c.to_vec());
-                future::ok::<Vec<u8>, hyper::Error>(v)
-            })
-            .map_err(|e| e.into())
-            .and_then(move |body| {
-                if let Ok(route_match) = router.recognize(parts.uri.path()) {
-                    match (route_match.handler, parts.method) {
-                        (&Route::Get(ref handler), Method::GET) => handler.lock().unwrap().handle(
-                            parts.uri,
-                            body.into(),
-                            &controller_id,
-                            &event_subscriptions,
-                            &config,
-                            &database,
-                            &accessories,
-                            &event_emitter,
-                        ),
-                        (&Route::Post(ref handler), Method::POST) => handler.lock().unwrap().handle(
-                            parts.uri,
-                            body.into(),
-                            &controller_id,
-                            &event_subscriptions,
-                            &config,
-                            &database,
-                            &accessories,
-                            &event_emitter,
-                        ),
-                        (&Route::GetPut { ref _get, ref _put }, Method::GET) => _get.lock().unwrap().handle(
-                            parts.uri,
-                            body.into(),
-                            &controller_id,
-                            &event_subscriptions,
-                            &config,
-                            &database,
-                            &accessories,
-                            &event_emitter,
-                        ),
-                        (&Route::GetPut { ref _get, ref _put }, Method::PUT) => _put.lock().unwrap().handle(
-                            parts.uri,
-                            body.into(),
-                            &controller_id,
-                            &event_subscriptions,
-                            &config,
-                            &database,
-                            &accessories,
-                            &event_emitter,
-                        ),
-                        _ => Box::new(future::result(status_response(StatusCode::BAD_REQUEST))),
-                    }
-                } else {
-                    Box::new(future::result(status_response(StatusCode::NOT_FOUND)))
+        Box::pin(async move {
+            let body = hyper::body::to_bytes(body).await?.to_vec();
+            let res = if let Ok(route_match) = router.recognize(parts.uri.path()) {
+                match (route_match.handler, parts.method) {
+                    (&Route::Get(ref handler), Method::GET) => handler.lock().unwrap().handle(
+                        parts.uri,
+                        body,
+                        &controller_id,
+                        &event_subscriptions,
+                        &config,
+                        &database,
+                        &accessories,
+                        &event_emitter,
+                    ),
+                    (&Route::Post(ref handler), Method::POST) => handler.lock().unwrap().handle(
+                        parts.uri,
+                        body,
+                        &controller_id,
+                        &event_subscriptions,
+                        &config,
+                        &database,
+                        &accessories,
+                        &event_emitter,
+                    ),
+                    (&Route::Put(ref handler), Method::PUT) => handler.lock().unwrap().handle(
+                        parts.uri,
+                        body,
+                        &controller_id,
+                        &event_subscriptions,
+                        &config,
+                        &database,
+                        &accessories,
+                        &event_emitter,
+                    ),
+                    (&Route::GetPut { ref _get, ref _put }, Method::GET) => _get.lock().unwrap().handle(
+                        parts.uri,
+                        body,
+                        &controller_id,
+                        &event_subscriptions,
+                        &config,
+                        &database,
+                        &accessories,
+                        &event_emitter,
+                    ),
+                    (&Route::GetPut { ref _get, ref _put }, Method::PUT) => _put.lock().unwrap().handle(
+                        parts.uri,
+                        body,
+                        &controller_id,
+                        &event_subscriptions,
+                        &config,
+                        &database,
+                        &accessories,
+                        &event_emitter,
+                    ),
+                    _ => Box::pin(future::ready(status_response(StatusCode::BAD_REQUEST))),
                 }
-            }),
-        )
+            } else {
+                Box::pin(future::ready(status_response(StatusCode::NOT_FOUND)))
+            };
+            res.await
+        })
     }
 }
 
 pub type EventSubscriptions = Arc<Mutex<Vec<(u64, u64)>>>;
 
+/// Senders closing the open sessions by their connection, e.g. when all pairings are removed.
+pub type SessionClosersPtr = Arc<Mutex<HashMap<ConnectionId, oneshot::Sender<()>>>>;
+
+/// Closes all open sessions right away, without answering the requests in flight.
+pub fn close_sessions(session_closers: &SessionClosersPtr) {
+    for (_, session_closer) in session_closers.lock().expect("couldn't access session_closers").drain() {
+        // the session may already be closing on its own
+        let _ = session_closer.send(());
+    }
+}
+
+/// Binds to `socket_addr` and returns the future running the accessory server. Every session is
+/// spawned onto the runtime the future runs on. Once `shutdown` resolves, the listener is closed and
+/// all sessions are closed after answering the requests in flight, which they get
+/// `SHUTDOWN_GRACE_PERIOD` for. Every session adds a sender to `session_closers` that closes it
+/// right away. The future resolves after the last session is gone.
 pub fn serve(
     socket_addr: &SocketAddr,
     config: &ConfigPtr,
     database: &DatabasePtr,
     accessories: &AccessoryList,
     event_emitter: &EventEmitterPtr,
-) -> Result<()> {
-    let listener = TcpListener::bind(socket_addr)?;
+    split_verifier: &SplitVerifierPtr,
+    session_closers: &SessionClosersPtr,
+    shutdown: oneshot::Receiver<()>,
+) -> Result<BoxFuture<'static, ()>> {
+    // the socket is bound right away so binding errors are returned before the server is spawned
+    let listener = net::TcpListener::bind(socket_addr)?;
+    listener.set_nonblocking(true)?;
 
     let config = config.clone();
     let database = database.clone();
     let accessories = accessories.clone();
     let event_emitter = event_emitter.clone();
+    let split_verifier = split_verifier.clone();
+    let session_closers = session_closers.clone();
+    let session_cache = Arc::new(Mutex::new(SessionCache::new()));
+    let pair_setup_lock = Arc::new(Mutex::new(None));
+
+    Ok(Box::pin(async move {
+        let listener = match TcpListener::from_std(listener) {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("{}", e);
+                return;
+            },
+        };
+        let shutdown = shutdown.shared();
+        // every session holds a clone of the sender, so the receiver ends once all sessions are closed
+        let (drain_sender, mut drain_receiver) = mpsc::channel::<()>(0);
 
-    let server = listener
-        .incoming()
-        .for_each(move |stream| {
+        let mut connection_count = 0;
+        loop {
+            let stream = match future::select(Box::pin(listener.accept()), shutdown.clone()).await {
+                Either::Left((Ok((stream, _)), _)) => stream,
+                Either::Left((Err(e), _)) => {
+                    error!("{}", e);
+                    break;
+                },
+                Either::Right(_) => break,
+            };
+
+            let connection_id = ConnectionId(connection_count);
+            connection_count += 1;
             let (encrypted_stream, stream_incoming, stream_outgoing, session_sender) = EncryptedStream::new(stream);
             let stream_wrapper = StreamWrapper::new(stream_incoming, stream_outgoing.clone());
             let event_subscriptions = Arc::new(Mutex::new(vec![]));
@@ -213,14 +301,38 @@ pub fn serve(
                 accessories.clone(),
                 event_emitter.clone(),
                 session_sender,
+                session_cache.clone(),
+                split_verifier.clone(),
+                pair_setup_lock.clone(),
+                connection_id,
             );
             let http = Http::new();
 
-            event_emitter
+            let (event_sender, event_receiver) = mpsc::unbounded();
+            let (event_batch_window, event_rate_limit) = {
+                let c = config.lock().expect("couldn't access config");
+                (c.event_batch_window, c.event_rate_limit)
+            };
+            tokio::spawn(EventQueue::new(
+                event_receiver,
+                stream_outgoing,
+                event_batch_window,
+                event_rate_limit,
+            ));
+
+            let listener_id = event_emitter
                 .lock()
                 .expect("couldn't add listener for characteristic value change events")
                 .add_listener(Box::new(move |event| match *event {
-                    Event::CharacteristicValueChanged { aid, iid, ref value } => {
+                    // a controller doesn't get events for its own writes
+                    Event::CharacteristicValueChanged { origin, .. } if origin == Some(connection_id) => {},
+                    Event::CharacteristicValueChanged {
+                        aid,
+                        iid,
+                        hap_type,
+                        ref value,
+                        ..
+                    } => {
                         let mut dropped_subscriptions = vec![];
                         for (i, &(s_aid, s_iid)) in event_subscriptions
                             .lock()
@@ -232,10 +344,10 @@ pub fn serve(
                                 let event = EventObject {
                                     aid,
                                     iid,
+                                    hap_type,
                                     value: value.clone(),
                                 };
-                                let event_res = event_response(vec![event]).expect("couldn't create event response");
-                                if stream_outgoing.unbounded_send(event_res).is_err() {
+                                if event_sender.unbounded_send(event).is_err() {
                                     dropped_subscriptions.push(i);
                                 }
                             }
@@ -248,15 +360,67 @@ pub fn serve(
                     _ => {},
                 }));
 
-            encrypted_stream
-                .map_err(|e| error!("{}", e))
-                .join(http.serve_connection(stream_wrapper, api).map_err(|e| error!("{}", e)))
-                .map(|_| ())
-                .then(|_| Ok(()))
-        })
-        .map_err(|e| error!("{}", e));
+            let (session_closer, session_closed) = oneshot::channel::<()>();
+            session_closers
+                .lock()
+                .expect("couldn't access session_closers")
+                .insert(connection_id, session_closer);
 
-    tokio::run(server);
+            let event_emitter = event_emitter.clone();
+            let session_closers = session_closers.clone();
+            let drain_sender = drain_sender.clone();
+            let session_shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                let (served_sender, served_receiver) = oneshot::channel::<()>();
+                let encrypted = async move {
+                    let mut encrypted_stream = encrypted_stream;
+                    let res = match future::select(&mut encrypted_stream, served_receiver).await {
+                        Either::Left((res, _)) => res,
+                        // the HTTP connection is closed, so only the responses still buffered are left
+                        Either::Right(_) => future::poll_fn(|cx| encrypted_stream.poll_flush(cx)).await,
+                    };
+                    if let Err(e) = res {
+                        error!("{}", e);
+                    }
+                };
+                let served_shutdown = session_shutdown.clone();
+                let served = async move {
+                    let mut connection = Box::pin(http.serve_connection(stream_wrapper, api));
+                    let res = match future::select(connection.as_mut(), served_shutdown).await {
+                        Either::Left((res, _)) => res,
+                        Either::Right(_) => {
+                            // requests in flight are still answered, but no new ones are accepted
+                            connection.as_mut().graceful_shutdown();
+                            connection.await
+                        },
+                    };
+                    if let Err(e) = res {
+                        error!("{}", e);
+                    }
+                    drop(served_sender);
+                };
+                let grace_period_over = async move {
+                    let _ = session_shutdown.await;
+                    sleep(SHUTDOWN_GRACE_PERIOD).await;
+                };
+                let session = future::select(Box::pin(future::join(encrypted, served)), Box::pin(grace_period_over));
+                future::select(session, session_closed).await;
+                session_closers
+                    .lock()
+                    .expect("couldn't access session_closers")
+                    .remove(&connection_id);
+
+                // the session is closed, so the listener has nothing to send events to anymore
+                event_emitter
+                    .lock()
+                    .expect("couldn't remove listener for characteristic value change events")
+                    .remove_listener(listener_id);
+                drop(drain_sender);
+            });
+        }
 
-    Ok(())
+        drop(listener);
+        drop(drain_sender);
+        while drain_receiver.next().await.is_some() {}
+    }))
 }
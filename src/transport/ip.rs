@@ -1,17 +1,36 @@
 use std::{
+    collections::HashMap,
     net::SocketAddr,
     sync::{Arc, Mutex},
 };
 
+use futures::{channel::oneshot, future::BoxFuture};
+use tokio::runtime::Runtime;
+
 use crate::{
     config::{Config, ConfigPtr},
-    db::{AccessoryList, AccessoryListMember, AccessoryListPtr, Database, DatabasePtr, FileStorage, Storage},
-    event::{Event, EventEmitter, EventEmitterPtr},
+    db::{
+        AccessPolicy,
+        AccessoryList,
+        AccessoryListMember,
+        AccessoryListPtr,
+        Database,
+        DatabasePtr,
+        EncryptionScope,
+        FileStorage,
+        KeyProvider,
+        PairSetupAttempts,
+        Storage,
+    },
+    event::{Event, EventEmitter, EventEmitterPtr, ListenerId},
     pin,
     protocol::Device,
     transport::{
         bonjour::StatusFlag,
-        http,
+        http::{
+            handler::pair_setup::SplitVerifierPtr,
+            server::{self, SessionClosersPtr},
+        },
         mdns::{Responder, ResponderPtr},
         Transport,
     },
@@ -27,6 +46,10 @@ pub struct IpTransport<S: Storage> {
     accessories: AccessoryList,
     event_emitter: EventEmitterPtr,
     mdns_responder: ResponderPtr,
+    split_verifier: SplitVerifierPtr,
+    session_closers: SessionClosersPtr,
+    shutdown: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+    listener_id: Arc<Mutex<Option<ListenerId>>>,
 }
 
 impl IpTransport<FileStorage> {
@@ -72,16 +95,78 @@ impl IpTransport<FileStorage> {
     ///
     /// //ip_transport.start().unwrap();
     /// ```
-    pub fn new(mut config: Config) -> Result<IpTransport<FileStorage>> {
+    pub fn new(config: Config) -> Result<IpTransport<FileStorage>> {
         let storage = FileStorage::new(&config.storage_path)?;
-        let database = Database::new_with_file_storage(&config.storage_path)?;
+        IpTransport::new_with_storage(config, storage)
+    }
+}
+
+impl<S: 'static + Storage + Clone + Send> IpTransport<S> {
+    /// Creates a new `IpTransport` that stores its data to the given `Storage` instead of a
+    /// `FileStorage` at `Config::storage_path`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hap::{db::InMemoryStorage, transport::IpTransport, Config};
+    ///
+    /// let config = Config {
+    ///     name: "Acme Lighting".into(),
+    ///     ..Default::default()
+    /// };
+    ///
+    /// let ip_transport = IpTransport::new_with_storage(config, InMemoryStorage::new()).unwrap();
+    /// ```
+    pub fn new_with_storage(config: Config, storage: S) -> Result<IpTransport<S>> {
+        let database = Database::new(Box::new(storage.clone()));
+        IpTransport::new_with_database(config, storage, database)
+    }
 
+    /// Creates a new `IpTransport` that stores its data to the given `Storage` and encrypts the
+    /// records in the given `EncryptionScope` at rest with the key supplied by `key_provider`. Records
+    /// stored in plain text before are encrypted right away.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hap::{
+    ///     db::{EncryptionScope, InMemoryStorage},
+    ///     transport::IpTransport,
+    ///     Config,
+    /// };
+    ///
+    /// let config = Config {
+    ///     name: "Acme Lighting".into(),
+    ///     ..Default::default()
+    /// };
+    /// let key = [7; 32];
+    ///
+    /// let ip_transport =
+    ///     IpTransport::new_with_encryption(config, InMemoryStorage::new(), key, EncryptionScope::DeviceAndPairings)
+    ///         .unwrap();
+    /// ```
+    pub fn new_with_encryption(
+        config: Config,
+        storage: S,
+        key_provider: impl KeyProvider + 'static + Send,
+        encryption_scope: EncryptionScope,
+    ) -> Result<IpTransport<S>> {
+        let mut database = Database::new(Box::new(storage.clone()));
+        database.set_encryption(key_provider, encryption_scope)?;
+        IpTransport::new_with_database(config, storage, database)
+    }
+
+    fn new_with_database(mut config: Config, storage: S, database: Database) -> Result<IpTransport<S>> {
         config.load_from(&storage)?;
         config.update_hash();
         config.save_to(&storage)?;
 
         let pin = pin::new(&config.pin)?;
         let device = Device::load_or_new(config.device_id.to_hex_string(), pin, &database)?;
+        // an accessory paired in an earlier run mustn't be announced as unpaired
+        if database.count_pairings()? > 0 {
+            config.status_flag = StatusFlag::Zero;
+        }
         let event_emitter = Arc::new(Mutex::new(EventEmitter::new()));
         let mdns_responder = Arc::new(Mutex::new(Responder::new(
             &config.name,
@@ -96,20 +181,28 @@ impl IpTransport<FileStorage> {
             accessories: AccessoryList::new(event_emitter.clone()),
             event_emitter,
             mdns_responder,
+            split_verifier: Arc::new(Mutex::new(None)),
+            session_closers: Arc::new(Mutex::new(HashMap::new())),
+            shutdown: Arc::new(Mutex::new(None)),
+            listener_id: Arc::new(Mutex::new(None)),
         };
         device.save_to(&ip_transport.database)?;
 
         Ok(ip_transport)
     }
-}
 
-impl Transport for IpTransport<FileStorage> {
-    fn start(&mut self) -> Result<()> {
-        self.mdns_responder
-            .lock()
-            .expect("couldn't access event_emitter")
-            .start();
+    /// Sets an `AccessPolicy` deciding which paired controllers may read or write which
+    /// Characteristics. Without an `AccessPolicy`, every paired controller has full access.
+    pub fn set_access_policy(&mut self, access_policy: impl AccessPolicy + 'static + Send) {
+        self.accessories.set_access_policy(access_policy)
+    }
 
+    /// Starts the mDNS announcement and returns the future running the accessory server without
+    /// blocking. The future has to be run on a Tokio runtime, e.g. by awaiting it or with
+    /// `tokio::spawn`. It resolves once the transport is stopped via `Transport::stop` and all
+    /// sessions are closed. `Transport::start` runs the same future on a new runtime and blocks until
+    /// it resolves.
+    pub fn serve(&mut self) -> Result<BoxFuture<'static, ()>> {
         let (ip, port) = {
             let c = self.config.lock().expect("couldn't access config");
             (c.ip, c.port)
@@ -118,7 +211,8 @@ impl Transport for IpTransport<FileStorage> {
         let config = self.config.clone();
         let database = self.database.clone();
         let mdns_responder = self.mdns_responder.clone();
-        self.event_emitter
+        let listener_id = self
+            .event_emitter
             .lock()
             .expect("couldn't access event_emitter")
             .add_listener(Box::new(move |event| match *event {
@@ -151,17 +245,83 @@ impl Transport for IpTransport<FileStorage> {
                 _ => {},
             }));
 
-        http::server::serve(
+        *self.listener_id.lock().expect("couldn't access listener_id") = Some(listener_id);
+
+        let (shutdown_sender, shutdown_receiver) = oneshot::channel();
+        *self.shutdown.lock().expect("couldn't access shutdown") = Some(shutdown_sender);
+
+        let server = server::serve(
             &SocketAddr::new(ip, port),
             &self.config,
             &self.database,
             &self.accessories,
             &self.event_emitter,
+            &self.split_verifier,
+            &self.session_closers,
+            shutdown_receiver,
         )?;
+
+        self.mdns_responder
+            .lock()
+            .expect("couldn't access mDNS responder")
+            .start();
+
+        Ok(server)
+    }
+
+    /// Removes all pairings, e.g. for a factory reset, so the accessory can be paired again. Also
+    /// closes all open sessions, forgets the SRP verifier kept for Split Pair Setup, lifts a Pair
+    /// Setup lock caused by too many unsuccessful attempts and sets the Bonjour Status Flag back to
+    /// `StatusFlag::NotPaired`.
+    pub fn reset_pairings(&mut self) -> Result<()> {
+        {
+            let d = self.database.lock().expect("couldn't access database");
+            for pairing in d.list_pairings()? {
+                d.delete_pairing(&pairing.id)?;
+            }
+            d.set_pair_setup_attempts(&PairSetupAttempts::default())?;
+        }
+
+        // sessions of controllers that aren't paired anymore mustn't stay usable
+        server::close_sessions(&self.session_closers);
+        *self.split_verifier.lock().expect("couldn't access split_verifier") = None;
+
+        {
+            let mut c = self.config.lock().expect("couldn't access config");
+            c.status_flag = StatusFlag::NotPaired;
+            self.mdns_responder
+                .lock()
+                .expect("couldn't access mDNS responder")
+                .update_txt_records(c.txt_records())?;
+        }
+
+        self.event_emitter
+            .lock()
+            .expect("couldn't access event_emitter")
+            .emit(&Event::DeviceUnpaired);
+
+        Ok(())
+    }
+}
+
+impl<S: 'static + Storage + Clone + Send> Transport for IpTransport<S> {
+    fn start(&mut self) -> Result<()> {
+        let server = self.serve()?;
+        Runtime::new()?.block_on(server);
         Ok(())
     }
 
     fn stop(&self) -> Result<()> {
+        if let Some(shutdown) = self.shutdown.lock().expect("couldn't access shutdown").take() {
+            // the server may already be gone, in which case there's nothing to shut down
+            let _ = shutdown.send(());
+        }
+        if let Some(listener_id) = self.listener_id.lock().expect("couldn't access listener_id").take() {
+            self.event_emitter
+                .lock()
+                .expect("couldn't access event_emitter")
+                .remove_listener(listener_id);
+        }
         self.mdns_responder
             .lock()
             .expect("couldn't access mDNS responder")
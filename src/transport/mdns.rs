@@ -57,18 +57,21 @@ impl Responder {
     }
 
     /// Stops mDNS announcement.
-    pub fn stop(&self) -> Result<()> {
-        if let Some(stop) = self.stop.clone() {
+    pub fn stop(&mut self) -> Result<()> {
+        if let Some(stop) = self.stop.take() {
             stop.send(())?;
         }
         Ok(())
     }
 
-    /// Stops mDNS announcement and restarts it with updated TXT records.
+    /// Updates the TXT records. A running mDNS announcement is stopped and restarted with the
+    /// updated TXT records.
     pub fn update_txt_records(&mut self, txt_records: [String; 8]) -> Result<()> {
-        self.stop()?;
         self.txt_records = txt_records;
-        self.start();
+        if self.stop.is_some() {
+            self.stop()?;
+            self.start();
+        }
         Ok(())
     }
 }
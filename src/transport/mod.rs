@@ -11,14 +11,14 @@ pub(crate) mod tcp;
 
 mod ip;
 
-pub use self::ip::IpTransport;
+pub use self::{http::Status, ip::IpTransport};
 
 /// `Transport` is implemented by the transport methods HAP supports. Currently, that's just
 /// `IpTransport`.
 pub trait Transport {
-    /// Starts the transport.
+    /// Starts the transport and blocks until it's stopped.
     fn start(&mut self) -> Result<()>;
-    /// Stops the transport.
+    /// Stops the transport and closes all open sessions.
     fn stop(&self) -> Result<()>;
     /// Adds an Accessory to the transport and returns a pointer to the added Accessory.
     fn add_accessory<A: 'static + AccessoryListMember + Send>(&mut self, accessory: A) -> Result<AccessoryListPtr>;
@@ -1,32 +1,30 @@
 use std::{
     cmp::min,
-    io::{self, ErrorKind, Read, Write},
+    future::Future,
+    io,
+    pin::Pin,
     sync::{Arc, Mutex},
+    task::{Context, Poll},
 };
 
 use byteorder::{ByteOrder, LittleEndian};
-use bytes::{buf::FromBuf, BytesMut};
+use bytes::BytesMut;
 use chacha20_poly1305_aead;
 use futures::{
-    sync::{
+    channel::{
         mpsc::{self, UnboundedReceiver, UnboundedSender},
         oneshot,
     },
-    try_ready,
-    Async::{self, NotReady, Ready},
-    Future,
-    Poll,
-    Sink,
     Stream,
 };
 use ring::{digest, hkdf, hmac};
 use tokio::{
-    io::{AsyncRead, AsyncWrite},
+    io::{AsyncRead, AsyncWrite, ReadBuf},
     net::TcpStream,
 };
 use uuid::Uuid;
 
-use crate::{protocol::IdPtr, Result};
+use crate::{protocol::IdPtr, Error, Result};
 
 pub struct StreamWrapper {
     incoming_receiver: UnboundedReceiver<Vec<u8>>,
@@ -45,78 +43,58 @@ impl StreamWrapper {
             incoming_buf: BytesMut::new(),
         }
     }
-
-    fn poll_receiver(&mut self) -> std::result::Result<usize, io::Error> {
-        match self.incoming_receiver.poll() {
-            Ok(NotReady) => Err(ErrorKind::WouldBlock.into()),
-            Ok(Ready(Some(incoming))) => {
-                self.incoming_buf.extend_from_slice(&incoming);
-                Ok(incoming.len())
-            },
-            Ok(Ready(None)) => Ok(0),
-            // Err(_) => Err(Error::from_str("couldn't poll receiver").into()),
-            Err(_) => Err(io::Error::new(io::ErrorKind::Other, "couldn't poll receiver")),
-        }
-    }
 }
 
-impl Read for StreamWrapper {
-    fn read(&mut self, buf: &mut [u8]) -> std::result::Result<usize, io::Error> {
-        self.poll_receiver()?;
-        let r_len = min(buf.len(), self.incoming_buf.len());
-        buf[..r_len].copy_from_slice(&self.incoming_buf[..r_len]);
+impl AsyncRead for StreamWrapper {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut ReadBuf) -> Poll<io::Result<()>> {
+        if self.incoming_buf.is_empty() {
+            match Pin::new(&mut self.incoming_receiver).poll_next(cx) {
+                Poll::Ready(Some(incoming)) => self.incoming_buf.extend_from_slice(&incoming),
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let r_len = min(buf.remaining(), self.incoming_buf.len());
+        buf.put_slice(&self.incoming_buf[..r_len]);
         self.incoming_buf.advance(r_len);
-        Ok(r_len)
+        Poll::Ready(Ok(()))
     }
 }
 
-impl Write for StreamWrapper {
-    fn write(&mut self, buf: &[u8]) -> std::result::Result<usize, io::Error> {
-        self.outgoing_sender.unbounded_send(buf.to_vec())
-            // .map_err(|_| Error::from_str("couldn't write").into())?;
+impl AsyncWrite for StreamWrapper {
+    fn poll_write(self: Pin<&mut Self>, _: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.outgoing_sender
+            .unbounded_send(buf.to_vec())
             .map_err(|_| io::Error::new(io::ErrorKind::Other, "couldn't write"))?;
-        Ok(buf.len())
+        Poll::Ready(Ok(buf.len()))
     }
 
-    fn flush(&mut self) -> std::result::Result<(), io::Error> {
-        self.outgoing_sender.poll_complete()
-            .map(|_| ())
-            // .map_err(|_| Error::from_str("couldn't flush").into())
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, "couldn't flush"))
-    }
-}
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> { Poll::Ready(Ok(())) }
 
-impl AsyncRead for StreamWrapper {}
-
-impl AsyncWrite for StreamWrapper {
-    fn shutdown(&mut self) -> Poll<(), io::Error> {
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
         // TODO - maybe do something useful here
-        Ok(Ready(()))
+        Poll::Ready(Ok(()))
     }
 }
 
+/// An established session. `controller_id` is `None` for transient sessions, which aren't bound to a
+/// pairing.
 pub struct Session {
-    pub controller_id: Uuid,
-    pub shared_secret: [u8; 32],
+    pub controller_id: Option<Uuid>,
+    pub codec: SessionCodec,
 }
 
+pub type SessionSenderPtr = Arc<Mutex<Option<oneshot::Sender<Session>>>>;
+
 pub struct EncryptedStream {
     stream: TcpStream,
     incoming_sender: UnboundedSender<Vec<u8>>,
     outgoing_receiver: UnboundedReceiver<Vec<u8>>,
     session_receiver: oneshot::Receiver<Session>,
     pub controller_id: IdPtr,
-    shared_secret: Option<[u8; 32]>,
-    decrypt_count: u64,
-    encrypt_count: u64,
-    encrypted_buf: BytesMut,
-    decrypted_buf: BytesMut,
-    packet_len: usize,
-    already_copied: usize,
-    already_read: usize,
-    decrypted_ready: bool,
-    missing_data_for_decrypted_buf: bool,
-    missing_data_for_encrypted_buf: bool,
+    codec: Option<SessionCodec>,
+    read_buf: BytesMut,
+    write_buf: BytesMut,
 }
 
 impl EncryptedStream {
@@ -138,17 +116,9 @@ impl EncryptedStream {
                 outgoing_receiver,
                 session_receiver: receiver,
                 controller_id: Arc::new(Mutex::new(None)),
-                shared_secret: None,
-                decrypt_count: 0,
-                encrypt_count: 0,
-                encrypted_buf: BytesMut::from_buf(vec![0; 1042]),
-                decrypted_buf: BytesMut::from_buf(vec![0; 1024]),
-                packet_len: 0,
-                already_copied: 0,
-                already_read: 0,
-                decrypted_ready: false,
-                missing_data_for_decrypted_buf: false,
-                missing_data_for_encrypted_buf: false,
+                codec: None,
+                read_buf: BytesMut::new(),
+                write_buf: BytesMut::new(),
             },
             incoming_receiver,
             outgoing_sender,
@@ -156,221 +126,208 @@ impl EncryptedStream {
         )
     }
 
-    fn read_decrypted(&mut self, buf: &mut [u8]) -> std::result::Result<usize, io::Error> {
-        if self.decrypted_ready {
-            let len = min(buf.len(), self.packet_len - 16);
-            buf[..len].copy_from_slice(&self.decrypted_buf[..len]);
-            self.already_copied = len;
-            if self.already_copied == (self.packet_len - 16) {
-                self.already_copied = 0;
-                self.decrypted_ready = false;
+    /// Passes the data read from the TCP stream on to the HTTP server. Once the session is
+    /// established, the data is decrypted frame by frame.
+    fn decode(&mut self) -> io::Result<()> {
+        if self.codec.is_none() {
+            if let Ok(Some(session)) = self.session_receiver.try_recv() {
+                *self.controller_id.lock().expect("couldn't access controller_id") = session.controller_id;
+                self.codec = Some(session.codec);
             }
+        }
 
-            return Ok(len);
+        match self.codec {
+            Some(ref mut codec) => {
+                let mut frames = Vec::new();
+                while let Some(frame) = codec.decode(&mut self.read_buf).map_err(into_io_error)? {
+                    frames.push(frame);
+                }
+                for frame in frames {
+                    self.send_incoming(frame)?;
+                }
+            },
+            None => {
+                let data = self.read_buf.take().to_vec();
+                self.send_incoming(data)?;
+            },
         }
 
-        Err(ErrorKind::WouldBlock.into())
+        Ok(())
     }
 
-    fn read_encrypted(&mut self, buf: &mut [u8]) -> std::result::Result<usize, io::Error> {
-        if self.missing_data_for_decrypted_buf {
-            let decrypted = decrypt_chunk(
-                &self.shared_secret.expect("missing shared secret"),
-                &self.encrypted_buf[..2],
-                &self.encrypted_buf[2..(self.packet_len - 14)],
-                &self.encrypted_buf[(self.packet_len - 14)..(self.packet_len + 2)],
-                &mut self.decrypt_count,
-            )
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, "decryption failed"))?;
-            self.decrypted_buf[..decrypted.len()].copy_from_slice(&decrypted);
-            self.missing_data_for_decrypted_buf = false;
-            self.decrypted_ready = true;
-
-            return self.read_decrypted(buf);
+    /// Buffers data from the HTTP server to be written to the TCP stream. Once the session is
+    /// established, the data is encrypted in frames of up to 1024 bytes.
+    fn encode(&mut self, data: &[u8]) -> io::Result<()> {
+        match self.codec {
+            Some(ref mut codec) => codec.encode(data, &mut self.write_buf).map_err(into_io_error),
+            None => {
+                self.write_buf.extend_from_slice(data);
+                Ok(())
+            },
         }
-
-        Err(ErrorKind::WouldBlock.into())
     }
 
-    fn read_stream(&mut self, buf: &mut [u8]) -> std::result::Result<usize, io::Error> {
-        if self.missing_data_for_encrypted_buf {
-            let r_len = self.stream.read(&mut self.encrypted_buf[self.already_read..])?;
-
-            if self.already_read + r_len == self.packet_len {
-                self.already_read = 0;
-                self.missing_data_for_encrypted_buf = false;
-                self.missing_data_for_decrypted_buf = true;
-                return self.read_encrypted(buf);
-            }
-            Err(ErrorKind::WouldBlock.into())
-        } else {
-            let r_len = self.stream.read(&mut self.encrypted_buf[self.already_read..2])?;
-            self.already_read += r_len;
-
-            if self.already_read == 2 {
-                self.packet_len = LittleEndian::read_u16(&self.encrypted_buf) as usize + 16;
-
-                self.missing_data_for_encrypted_buf = true;
-                let r_len = self.stream.read(&mut self.encrypted_buf[self.already_read..])?;
-                if r_len == self.packet_len {
-                    self.already_read = 0;
-                    self.missing_data_for_encrypted_buf = false;
-                    self.missing_data_for_decrypted_buf = true;
-                    self.read_encrypted(buf)
-                } else {
-                    self.already_read += r_len;
-                    Err(ErrorKind::WouldBlock.into())
-                }
-            } else {
-                Err(ErrorKind::WouldBlock.into())
-            }
-        }
+    fn send_incoming(&mut self, data: Vec<u8>) -> io::Result<()> {
+        self.incoming_sender
+            .unbounded_send(data)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "couldn't send incoming data"))
     }
 
-    fn poll_incoming(&mut self) -> Poll<(), io::Error> {
+    fn poll_incoming(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
         let mut data = [0; 1536];
         loop {
-            match self.read(&mut data) {
-                Err(e) => match e.kind() {
-                    ErrorKind::WouldBlock => {
-                        return Ok(NotReady);
-                    },
-                    _ => {
-                        return Err(e);
-                    },
-                },
-                Ok(r_len) => {
-                    if r_len == 0 {
-                        return Ok(Ready(()));
+            let mut buf = ReadBuf::new(&mut data);
+            match Pin::new(&mut self.stream).poll_read(cx, &mut buf) {
+                Poll::Ready(Ok(())) => {
+                    if buf.filled().is_empty() {
+                        return Poll::Ready(Ok(()));
                     }
-                    self.incoming_sender.unbounded_send(data[..r_len].to_vec())
-                        // .map_err(|_| Error::from_str("couldn't send incoming data").into())?;
-                        .map_err(|_| io::Error::new(io::ErrorKind::Other, "couldn't send incoming data"))?;
+                    self.read_buf.extend_from_slice(buf.filled());
+                    self.decode()?;
                 },
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
             }
         }
     }
 
-    fn poll_outgoing(&mut self) -> Poll<(), ()> {
+    fn poll_outgoing(&mut self, cx: &mut Context) -> io::Result<()> {
         loop {
-            match try_ready!(self.outgoing_receiver.poll()) {
-                None => {
-                    return Ok(Ready(()));
-                },
-                Some(data) => {
-                    self.write(&data).map_err(|_| ())?;
-                },
+            while !self.write_buf.is_empty() {
+                match Pin::new(&mut self.stream).poll_write(cx, &self.write_buf) {
+                    Poll::Ready(Ok(0)) => return Err(io::ErrorKind::WriteZero.into()),
+                    Poll::Ready(Ok(w_len)) => self.write_buf.advance(w_len),
+                    Poll::Ready(Err(e)) => return Err(e),
+                    Poll::Pending => return Ok(()),
+                }
             }
+            match Pin::new(&mut self.outgoing_receiver).poll_next(cx) {
+                Poll::Ready(Some(data)) => self.encode(&data)?,
+                Poll::Ready(None) | Poll::Pending => return Ok(()),
+            }
+        }
+    }
+
+    /// Writes all data the HTTP server has sent so far to the TCP stream. Resolves once nothing is
+    /// left to write.
+    pub fn poll_flush(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.poll_outgoing(cx)?;
+        if self.write_buf.is_empty() {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
         }
     }
 }
 
 impl Future for EncryptedStream {
-    type Error = io::Error;
-    type Item = ();
+    type Output = io::Result<()>;
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        self.poll_outgoing()
-            // .map_err(|_| Error::from_str("couldn't receive outgoing data").into())?;
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, "couldn't receive incoming data"))?;
-
-        self.poll_incoming()
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.poll_outgoing(cx)?;
+        self.poll_incoming(cx)
     }
 }
 
-impl Read for EncryptedStream {
-    fn read(&mut self, buf: &mut [u8]) -> std::result::Result<usize, io::Error> {
-        if self.shared_secret.is_none() {
-            match self.session_receiver.poll() {
-                Ok(Async::Ready(session)) => {
-                    *self.controller_id.lock().expect("couldn't access controller_id") = Some(session.controller_id);
-                    self.shared_secret = Some(session.shared_secret);
-                },
-                _ => {
-                    return self.stream.read(buf);
-                },
-            }
-        }
+/// Maximum length of the encrypted data of a single frame.
+const MAX_FRAME_LEN: usize = 1024;
+/// Length of the little-endian length prefix of a frame, which doubles as its AAD.
+const LEN_PREFIX_LEN: usize = 2;
+/// Length of the Poly1305 auth tag of a frame.
+const AUTH_TAG_LEN: usize = 16;
+
+/// Encoder and decoder for the frames of an established HAP session.
+///
+/// Every frame consists of the 2-byte little-endian length of its data, which is also used as the
+/// AAD, up to 1024 bytes of ChaCha20-Poly1305 encrypted data and the 16-byte auth tag. Both
+/// directions use their own key and a nonce counter that's incremented with every frame.
+pub struct SessionCodec {
+    read_key: [u8; 32],
+    write_key: [u8; 32],
+    decrypt_count: u64,
+    encrypt_count: u64,
+}
 
-        match self.read_decrypted(buf) {
-            Ok(size) => Ok(size),
-            Err(_) => match self.read_encrypted(buf) {
-                Ok(size) => Ok(size),
-                Err(_) => self.read_stream(buf),
-            },
-        }
+impl SessionCodec {
+    /// Creates a new `SessionCodec` for the shared secret negotiated during Pair Verify.
+    pub fn new(shared_secret: &[u8; 32]) -> SessionCodec {
+        SessionCodec::from_keys(compute_read_key(shared_secret), compute_write_key(shared_secret))
     }
-}
 
-impl Write for EncryptedStream {
-    fn write(&mut self, buf: &[u8]) -> std::result::Result<usize, io::Error> {
-        if let Some(shared_secret) = self.shared_secret {
-            let mut write_buf = BytesMut::from_buf(buf);
-
-            while write_buf.len() > 1024 {
-                let (aad, chunk, auth_tag) = encrypt_chunk(&shared_secret, &write_buf[..1024], &mut self.encrypt_count)
-                    .map_err(|_| io::Error::new(io::ErrorKind::Other, "encryption failed"))?;
-                self.stream.write_all(&aad)?;
-                self.stream.write_all(&chunk)?;
-                self.stream.write_all(&auth_tag)?;
-                write_buf.advance(1024);
-            }
+    /// Creates a new `SessionCodec` from the keys for decrypting incoming and encrypting outgoing
+    /// frames.
+    pub fn from_keys(read_key: [u8; 32], write_key: [u8; 32]) -> SessionCodec {
+        SessionCodec {
+            read_key,
+            write_key,
+            decrypt_count: 0,
+            encrypt_count: 0,
+        }
+    }
 
-            let (aad, chunk, auth_tag) = encrypt_chunk(&shared_secret, &write_buf, &mut self.encrypt_count)
-                .map_err(|_| io::Error::new(io::ErrorKind::Other, "encryption failed"))?; // TODO - make this better
-            self.stream.write_all(&aad)?;
-            self.stream.write_all(&chunk)?;
-            self.stream.write_all(&auth_tag)?;
-            Ok(buf.len())
-        } else {
-            self.stream.write(buf)
+    /// Takes the next complete frame off the front of `src` and returns its decrypted data. Returns
+    /// `None` and leaves `src` untouched if it doesn't contain a complete frame yet.
+    pub fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Vec<u8>>> {
+        if src.len() < LEN_PREFIX_LEN {
+            return Ok(None);
+        }
+        let data_len = LittleEndian::read_u16(&src[..LEN_PREFIX_LEN]) as usize;
+        if data_len > MAX_FRAME_LEN {
+            return Err(Error::from_str("frame length exceeds the maximum of 1024 bytes"));
+        }
+        let frame_len = LEN_PREFIX_LEN + data_len + AUTH_TAG_LEN;
+        if src.len() < frame_len {
+            return Ok(None);
         }
+
+        let frame = src.split_to(frame_len);
+        let (aad, rest) = frame.split_at(LEN_PREFIX_LEN);
+        let (data, auth_tag) = rest.split_at(data_len);
+        let decrypted = decrypt_chunk(&self.read_key, aad, data, auth_tag, &mut self.decrypt_count)?;
+
+        Ok(Some(decrypted))
     }
 
-    fn flush(&mut self) -> std::result::Result<(), io::Error> { self.stream.flush() }
+    /// Encrypts `data` in frames of up to 1024 bytes and appends them to `dst`.
+    pub fn encode(&mut self, data: &[u8], dst: &mut BytesMut) -> Result<()> {
+        for chunk in data.chunks(MAX_FRAME_LEN) {
+            let (aad, encrypted, auth_tag) = encrypt_chunk(&self.write_key, chunk, &mut self.encrypt_count)?;
+            dst.reserve(LEN_PREFIX_LEN + encrypted.len() + AUTH_TAG_LEN);
+            dst.extend_from_slice(&aad);
+            dst.extend_from_slice(&encrypted);
+            dst.extend_from_slice(&auth_tag);
+        }
+        Ok(())
+    }
 }
 
-impl AsyncRead for EncryptedStream {}
+fn into_io_error(err: Error) -> io::Error { io::Error::new(io::ErrorKind::InvalidData, err.to_string()) }
 
-impl AsyncWrite for EncryptedStream {
-    fn shutdown(&mut self) -> Poll<(), io::Error> { AsyncWrite::shutdown(&mut self.stream) }
+fn compute_nonce(count: u64) -> [u8; 12] {
+    let mut nonce = [0; 12];
+    LittleEndian::write_u64(&mut nonce[4..], count);
+    nonce
 }
 
-fn decrypt_chunk(
-    shared_secret: &[u8; 32],
-    aad: &[u8],
-    data: &[u8],
-    auth_tag: &[u8],
-    count: &mut u64,
-) -> Result<Vec<u8>> {
+fn decrypt_chunk(key: &[u8; 32], aad: &[u8], data: &[u8], auth_tag: &[u8], count: &mut u64) -> Result<Vec<u8>> {
     let mut decrypted_data = Vec::new();
-    let read_key = compute_read_key(shared_secret);
+    let nonce = compute_nonce(*count);
 
-    let mut nonce = vec![0; 4];
-    let mut suffix = vec![0; 8];
-    LittleEndian::write_u64(&mut suffix, *count);
-    nonce.extend(suffix);
+    chacha20_poly1305_aead::decrypt(key, &nonce, aad, data, auth_tag, &mut decrypted_data)?;
     *count += 1;
 
-    chacha20_poly1305_aead::decrypt(&read_key, &nonce, aad, &data, auth_tag, &mut decrypted_data)?;
-
     Ok(decrypted_data)
 }
 
-fn encrypt_chunk(shared_secret: &[u8; 32], data: &[u8], count: &mut u64) -> Result<([u8; 2], Vec<u8>, [u8; 16])> {
+fn encrypt_chunk(key: &[u8; 32], data: &[u8], count: &mut u64) -> Result<([u8; 2], Vec<u8>, [u8; 16])> {
     let mut encrypted_data = Vec::new();
-    let write_key = compute_write_key(shared_secret);
-
-    let mut nonce = vec![0; 4];
-    let mut suffix = vec![0; 8];
-    LittleEndian::write_u64(&mut suffix, *count);
-    nonce.extend(suffix);
-    *count += 1;
+    let nonce = compute_nonce(*count);
 
     let mut aad = [0; 2];
     LittleEndian::write_u16(&mut aad, data.len() as u16);
 
-    let auth_tag = chacha20_poly1305_aead::encrypt(&write_key, &nonce, &aad, &data, &mut encrypted_data)?;
+    let auth_tag = chacha20_poly1305_aead::encrypt(key, &nonce, &aad, data, &mut encrypted_data)?;
+    *count += 1;
 
     Ok((aad, encrypted_data, auth_tag))
 }
@@ -389,3 +346,119 @@ fn compute_key(shared_secret: &[u8; 32], info: &[u8]) -> [u8; 32] {
     hkdf::extract_and_expand(&salt, shared_secret, &info, &mut key);
     key
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the codecs of both ends of a session, the accessory's and the controller's.
+    fn session() -> (SessionCodec, SessionCodec) {
+        let accessory = SessionCodec::from_keys([1; 32], [2; 32]);
+        let controller = SessionCodec::from_keys([2; 32], [1; 32]);
+        (accessory, controller)
+    }
+
+    fn data(len: usize) -> Vec<u8> { (0..len).map(|i| i as u8).collect() }
+
+    fn decode_all(codec: &mut SessionCodec, src: &mut BytesMut) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+        while let Some(frame) = codec.decode(src).unwrap() {
+            frames.push(frame);
+        }
+        frames
+    }
+
+    #[test]
+    fn round_trip_across_frame_boundary() {
+        for &len in &[0, 1, 1023, 1024, 1025, 2048, 2500] {
+            let (mut accessory, mut controller) = session();
+            let mut buf = BytesMut::new();
+            accessory.encode(&data(len), &mut buf).unwrap();
+
+            let frames = decode_all(&mut controller, &mut buf);
+            let expected: Vec<Vec<u8>> = data(len).chunks(MAX_FRAME_LEN).map(<[u8]>::to_vec).collect();
+            assert_eq!(frames, expected);
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn decode_byte_by_byte() {
+        let (mut accessory, mut controller) = session();
+        let mut encoded = BytesMut::new();
+        accessory.encode(&data(1500), &mut encoded).unwrap();
+
+        let mut src = BytesMut::new();
+        let mut frames = Vec::new();
+        for &byte in encoded.iter() {
+            src.extend_from_slice(&[byte]);
+            let buffered = src.clone();
+            match controller.decode(&mut src).unwrap() {
+                Some(frame) => frames.push(frame),
+                None => assert_eq!(src, buffered),
+            }
+        }
+
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames.concat(), data(1500));
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn decode_multiple_frames_in_one_buffer() {
+        let (mut accessory, mut controller) = session();
+        let mut buf = BytesMut::new();
+        accessory.encode(b"first", &mut buf).unwrap();
+        accessory.encode(b"second", &mut buf).unwrap();
+        accessory.encode(b"third", &mut buf).unwrap();
+
+        let frames = decode_all(&mut controller, &mut buf);
+        assert_eq!(frames, vec![b"first".to_vec(), b"second".to_vec(), b"third".to_vec()]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn reject_oversized_frame_length() {
+        let (_, mut controller) = session();
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&[0x01, 0x04]);
+        buf.extend_from_slice(&[0; MAX_FRAME_LEN + 1 + AUTH_TAG_LEN]);
+
+        assert!(controller.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn reject_tampered_auth_tag() {
+        let (mut accessory, mut controller) = session();
+        let mut buf = BytesMut::new();
+        accessory.encode(b"data", &mut buf).unwrap();
+        let last = buf.len() - 1;
+        buf[last] ^= 0x01;
+
+        assert!(controller.decode(&mut buf).is_err());
+        assert_eq!(controller.decrypt_count, 0);
+    }
+
+    #[test]
+    fn nonce_counters_advance_in_both_directions() {
+        let (mut accessory, mut controller) = session();
+
+        let mut to_controller = BytesMut::new();
+        accessory.encode(&data(2500), &mut to_controller).unwrap();
+        assert_eq!(accessory.encrypt_count, 3);
+        assert_eq!(decode_all(&mut controller, &mut to_controller).len(), 3);
+        assert_eq!(controller.decrypt_count, 3);
+
+        let mut to_accessory = BytesMut::new();
+        controller.encode(b"request", &mut to_accessory).unwrap();
+        assert_eq!(controller.encrypt_count, 1);
+        // a replayed frame was encrypted with an earlier nonce and doesn't decrypt anymore
+        let replayed = to_accessory.clone();
+        assert_eq!(accessory.decode(&mut to_accessory).unwrap(), Some(b"request".to_vec()));
+        assert_eq!(accessory.decrypt_count, 1);
+        assert!(accessory.decode(&mut replayed.clone()).is_err());
+
+        assert_eq!(accessory.encrypt_count, 3);
+        assert_eq!(controller.decrypt_count, 3);
+    }
+}